@@ -9,6 +9,7 @@ use render;
 use rustc_serialize::Decodable;
 use rustc_serialize::json::Decoder;
 use rustless::{Application, Api, Nesting};
+use scene::World;
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::Write;
@@ -28,9 +29,9 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
         let work = work.clone();
         thread::spawn(move || {
             let mut xi = StdRng::new().unwrap();
-            for (session, tile, tx) in WorkIterator::new(&work) {
-                let session: Arc<Session> = session;
-                render::render(&mut xi, &*session, tile, tx);
+            for (world, tile, tx) in WorkIterator::new(&work) {
+                let world: Arc<World> = world;
+                render::render(&mut xi, &*world, tile, tx);
             }
         });
     }
@@ -43,7 +44,7 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
                     let session = Session::decode(&mut Decoder::new(params.clone())).unwrap();
                     let mut sessions = sessions.lock().unwrap();
                     let session_id = sessions.len().to_string();
-                    sessions.insert(session_id.clone(), Arc::new(session));
+                    sessions.insert(session_id.clone(), Arc::new(World::new(session)));
                     client.text(session_id)
                 })
             });
@@ -52,11 +53,11 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
                 let sessions = sessions.clone();
                 endpoint.handle(move |client, params| {
                     let session_id = params.find("session_id").unwrap().as_string().unwrap();
-                    let session = sessions.lock().unwrap().get(session_id).unwrap().clone();
+                    let world = sessions.lock().unwrap().get(session_id).unwrap().clone();
                     let task = Task::decode(&mut Decoder::new(params.clone())).unwrap();
                     tx_work.lock()
                            .unwrap()
-                           .send((session,
+                           .send((world,
                                   task.tile,
                                   move |image: Vec<u8>| {
                                let mut e = GzEncoder::new(Vec::new(), Compression::Default);
@@ -26,9 +26,137 @@ impl Ray {
 #[derive(Clone, RustcDecodable, RustcEncodable)]
 pub enum Refl {
     Diff,
+    // Oren–Nayar rough diffuse, with the standard deviation of facet slopes in degrees.
+    OrenNayar(Param<f64>),
     Spec,
-    Refr,
-    Mix(f64, Box<Refl>, Box<Refl>),
+    // Index of refraction and absorption coefficient of the interior.
+    Refr(f64, Vector),
+    // Rough conductor: GGX roughness, then the real and imaginary parts of the index of refraction
+    // per colour channel.
+    Metal(Param<f64>, Param<Vector>, Param<Vector>),
+    // Rough glass: GGX roughness, then index of refraction and absorption as for Refr.
+    RoughRefr(f64, f64, Vector),
+    // Disney-style principled material, taking its base colour from the shape.
+    Principled(Principled),
+    // Dielectric coat with an index of refraction and GGX roughness, over a base material.
+    Coat(Param<f64>, Param<f64>, Box<Refl>),
+    Mix(Param<f64>, Box<Refl>, Box<Refl>),
+    // No surface at all: rays pass straight through, so the shape only bounds its medium.
+    Null,
+    // Smooth dielectric over a scattering interior, with an index of refraction and the mean free
+    // path per colour channel. The shape's colour sets how much light the interior gives back.
+    Subsurface(f64, Vector),
+}
+
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Principled {
+    pub base_color: Param<Vector>,
+    pub metallic: Param<f64>,
+    pub roughness: Param<f64>,
+    pub specular: Param<f64>,
+    pub clearcoat: Param<f64>,
+    pub sheen: Param<f64>,
+    pub transmission: Param<f64>,
+}
+
+// A material parameter that is either the same everywhere or read from a texture, an index into
+// Session::textures, at each point. A number read from a texture is the average of its channels.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub enum Param<T> {
+    Value(T),
+    Texture(usize),
+}
+
+// Indices into Session::textures of images that scale a shape's colour and emission, replace the
+// roughness of its material, or perturb its normal through a tangent-space normal map or a height
+// map, whose slopes are multiplied by `bump_scale`.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Textures {
+    pub color: Option<usize>,
+    pub emission: Option<usize>,
+    pub roughness: Option<usize>,
+    pub normal: Option<usize>,
+    pub bump: Option<usize>,
+    pub bump_scale: f64,
+}
+
+impl Textures {
+    pub const fn none() -> Self {
+        Textures {
+            color: None,
+            emission: None,
+            roughness: None,
+            normal: None,
+            bump: None,
+            bump_scale: 1.0,
+        }
+    }
+}
+
+// Linear RGB image, stored row by row from the top, three channels per pixel.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<f32>,
+}
+
+// Patterns that run from 0 to 1, with the number of octaves of noise for those built on noise.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub enum Pattern {
+    Checker,
+    Gradient,
+    Noise(usize),
+    Marble(usize),
+    Wood(usize),
+}
+
+// A pattern evaluated at the hit's UV coordinates or its position, multiplied by `scale`, that
+// blends from colour `a` to colour `b`.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub uv: bool,
+    pub scale: f64,
+    pub a: Vector,
+    pub b: Vector,
+}
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum Texture {
+    Image(Image),
+    Procedural(Procedural),
+}
+
+// A participating medium such as fog, smoke or milk: absorption and scattering coefficients per
+// unit length for each channel, and the Henyey–Greenstein asymmetry `g`, from -1 for light
+// scattered back the way it came to 1 for light scattered onwards. With a `grid`, an index into
+// Session::grids, the coefficients are scaled by the grid's density from place to place.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Medium {
+    pub absorption: Vector,
+    pub scattering: Vector,
+    pub g: f64,
+    pub grid: Option<usize>,
+}
+
+// A medium filling the space outside every shape, within the box from `lower` to `upper` if it has
+// one. Fog without a box fills all of space, so no light from outside the scene gets through it.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Fog {
+    pub medium: Medium,
+    pub bounds: Option<(Vector, Vector)>,
+}
+
+// Densities at the centres of a grid of voxels filling the box from `lower` to `upper`, with x
+// varying fastest, then y, then z. `max_density` is the largest of them.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Grid {
+    pub lower: Vector,
+    pub upper: Vector,
+    pub resolution: (usize, usize, usize),
+    pub density: Vec<f32>,
+    pub max_density: f64,
 }
 
 #[derive(Clone, RustcDecodable, RustcEncodable)]
@@ -38,6 +166,9 @@ pub struct Sphere {
     pub e: Vector,
     pub c: Vector,
     pub refl: Refl,
+    pub textures: Textures,
+    // Fills the interior, in place of the absorption of a glass material.
+    pub medium: Option<Medium>,
 }
 
 impl Sphere {
@@ -48,10 +179,83 @@ impl Sphere {
             e: e,
             c: c,
             refl: refl,
+            textures: Textures::none(),
+            medium: None,
         }
     }
 }
 
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Triangle {
+    pub v0: Vector,
+    pub v1: Vector,
+    pub v2: Vector,
+    pub e: Vector,
+    pub c: Vector,
+    pub refl: Refl,
+    pub textures: Textures,
+}
+
+impl Triangle {
+    pub const fn new(v0: Vector, v1: Vector, v2: Vector, e: Vector, c: Vector, refl: Refl) -> Self {
+        Triangle {
+            v0: v0,
+            v1: v1,
+            v2: v2,
+            e: e,
+            c: c,
+            refl: refl,
+            textures: Textures::none(),
+        }
+    }
+}
+
+// Indexed triangle mesh. `normals` is either empty, for flat shading, or holds one normal per
+// vertex, and likewise `uvs` for texture coordinates.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Mesh {
+    pub vertices: Vec<Vector>,
+    pub normals: Vec<Vector>,
+    pub uvs: Vec<(f64, f64)>,
+    pub faces: Vec<(usize, usize, usize)>,
+    pub e: Vector,
+    pub c: Vector,
+    pub refl: Refl,
+    pub textures: Textures,
+    // Fills the interior of a closed mesh, as for a sphere.
+    pub medium: Option<Medium>,
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vector>,
+               normals: Vec<Vector>,
+               uvs: Vec<(f64, f64)>,
+               faces: Vec<(usize, usize, usize)>,
+               e: Vector,
+               c: Vector,
+               refl: Refl)
+               -> Self {
+        Mesh {
+            vertices: vertices,
+            normals: normals,
+            uvs: uvs,
+            faces: faces,
+            e: e,
+            c: c,
+            refl: refl,
+            textures: Textures::none(),
+            medium: None,
+        }
+    }
+}
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum Shape {
+    Sphere(Sphere),
+    Triangle(Triangle),
+    Mesh(Mesh),
+}
+
 #[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
 pub struct Rectangle {
     pub left: usize,
@@ -86,23 +290,97 @@ impl Task {
     }
 }
 
+// Equirectangular image of the light arriving from every direction outside the scene, turned by
+// `rotation` radians about the y axis and scaled by `intensity`.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct EnvironmentMap {
+    pub image: Image,
+    pub rotation: f64,
+    pub intensity: f64,
+}
+
+// Preetham's daylight model for a sky of the given turbidity, with a sun in direction `sun` whose
+// disc has an angular radius of `sun_radius` radians. Luminance is in kcd/m², scaled by
+// `intensity`.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct Sky {
+    pub sun: Vector,
+    pub turbidity: f64,
+    pub sun_radius: f64,
+    pub intensity: f64,
+}
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum Environment {
+    Map(EnvironmentMap),
+    Sky(Sky),
+}
+
+// Candelas from an IES LM-63 file at each pair of vertical angle, measured from the light's axis,
+// and horizontal angle about it, both in degrees. `candela` lists the vertical angles for each
+// horizontal angle in turn.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Profile {
+    pub vertical: Vec<f64>,
+    pub horizontal: Vec<f64>,
+    pub candela: Vec<f64>,
+}
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum LightKind {
+    Point,
+    // Cosines of the half-angles of the cone lit fully and of the cone outside which it is dark.
+    Spot(f64, f64),
+    // Shines along its direction from outside the scene, with `intensity` as irradiance.
+    Directional,
+    // Horizontal angles are measured from a direction turned by this many radians about the axis.
+    Ies(Profile, f64),
+}
+
+// A light with no area, which rays can never hit and only light sampling finds. `direction` is the
+// axis of a spot or IES light and the direction of a directional light, and `intensity` is the
+// radiant intensity, scaled by the profile of an IES light.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: Vector,
+    pub direction: Vector,
+    pub intensity: Vector,
+}
+
 #[derive(Clone, RustcDecodable, RustcEncodable)]
 pub struct Session {
     pub width: usize,
     pub height: usize,
     pub samples: usize,
     pub camera: Ray,
-    pub scene: Vec<Sphere>,
+    pub scene: Vec<Shape>,
+    pub textures: Vec<Texture>,
+    pub grids: Vec<Grid>,
+    pub environment: Option<Environment>,
+    pub lights: Vec<Light>,
+    pub fog: Option<Fog>,
 }
 
 impl Session {
-    pub fn new(width: usize, height: usize, samples: usize, camera: Ray, scene: &[Sphere]) -> Self {
+    pub fn new(width: usize,
+               height: usize,
+               samples: usize,
+               camera: Ray,
+               scene: Vec<Shape>,
+               textures: Vec<Texture>)
+               -> Self {
         Session {
             width: width,
             height: height,
             samples: samples,
             camera: camera,
-            scene: scene.to_vec(),
+            scene: scene,
+            textures: textures,
+            grids: Vec::new(),
+            environment: None,
+            lights: Vec::new(),
+            fog: None,
         }
     }
 }
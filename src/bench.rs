@@ -0,0 +1,62 @@
+use api::{Ray, Refl, Session, Shape, Sphere, Vector};
+use bvh;
+use rand::{Rng, SeedableRng, StdRng};
+use scene::World;
+use std::error::Error;
+use std::time::Instant;
+use super::Args;
+
+fn seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
+}
+
+fn trace<F: Fn(Ray) -> bool>(name: &str, rays: &[Ray], f: F) -> usize {
+    let start = Instant::now();
+    let hits = rays.iter().filter(|&&ray| f(ray)).count();
+    println!("{:>6}: {:.0} rays/sec", name, rays.len() as f64 / seconds(start));
+    hits
+}
+
+pub fn run(args: &Args) -> Result<i32, Box<Error>> {
+    let spheres = args.flag_spheres.unwrap_or(10000);
+    let mut xi = StdRng::from_seed(&[1][..]);
+    let scene = (0..spheres)
+                    .map(|_| {
+                        let p = Vector::new(xi.gen_range(0.0, 100.0),
+                                            xi.gen_range(0.0, 100.0),
+                                            xi.gen_range(0.0, 100.0));
+                        Shape::Sphere(Sphere::new(xi.gen_range(0.2, 2.0),
+                                                  p,
+                                                  Vector::zero(),
+                                                  Vector::new(0.75, 0.75, 0.75),
+                                                  Refl::Diff))
+                    })
+                    .collect();
+
+    let origin = Vector::new(50.0, 50.0, 250.0);
+    let camera = Ray::new(origin, Vector::new(0.0, 0.0, -1.0));
+    let start = Instant::now();
+    let world = World::new(Session::new(1, 1, 1, camera, scene, Vec::new()));
+    println!("{} spheres, BVH built in {:.3} sec", spheres, seconds(start));
+
+    let rays: Vec<Ray> = (0..100000)
+                             .map(|_| {
+                                 let target = Vector::new(xi.gen_range(0.0, 100.0),
+                                                          xi.gen_range(0.0, 100.0),
+                                                          xi.gen_range(0.0, 100.0));
+                                 Ray::new(origin, (target - origin).norm())
+                             })
+                             .collect();
+
+    let linear_hits = trace("linear",
+                            &rays,
+                            |ray| bvh::intersect_linear(&world.session.scene, ray).is_some());
+    let bvh_hits = trace("bvh", &rays, |ray| world.intersect(ray).is_some());
+    if linear_hits != bvh_hits {
+        println!("Hit counts differ: {} linear, {} BVH", linear_hits, bvh_hits);
+        return Ok(1);
+    }
+
+    Ok(0)
+}
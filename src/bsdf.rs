@@ -0,0 +1,1400 @@
+#![allow(non_snake_case)]
+use api::{Medium, Param, Principled, Ray, Refl, Texture, Vector};
+use rand::Rng;
+use std::f64;
+
+// The point on a surface that a path has reached. Directions are in world space: `dir` is the
+// direction the path arrives along, `norm` is the shading normal, `nc` is the index of refraction
+// of the medium on the far side of the surface, and `color` is the surface colour. A `roughness`,
+// such as one from a texture, takes the place of the material's own. Parameters that come from
+// `textures` are looked up at `uv` and `pos`.
+pub struct Surface<'a> {
+    pub pos: Vector,
+    pub dir: Vector,
+    pub norm: Vector,
+    pub nc: f64,
+    pub color: Vector,
+    pub roughness: Option<f64>,
+    pub uv: (f64, f64),
+    pub textures: &'a [Texture],
+}
+
+impl Param<f64> {
+    fn at(&self, s: &Surface) -> f64 {
+        match self {
+            &Param::Value(x) => x,
+            &Param::Texture(i) => {
+                let x = s.textures[i].lookup(s.uv, s.pos);
+                (x.x + x.y + x.z) / 3.0
+            }
+        }
+    }
+}
+
+impl Param<Vector> {
+    fn at(&self, s: &Surface) -> Vector {
+        match self {
+            &Param::Value(x) => x,
+            &Param::Texture(i) => s.textures[i].lookup(s.uv, s.pos),
+        }
+    }
+}
+
+fn clamp(x: f64) -> f64 {
+    x.max(0.0).min(1.0)
+}
+
+// A material's scattering at a surface.
+pub trait Bsdf: Send + Sync {
+    // Casts the rays that continue the path from bounce `depth`, each with its weight (BSDF times
+    // cosine over pdf) and its pdf, which is None for a delta lobe.
+    fn sample(&self,
+              depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>));
+
+    // BSDF times cosine for light arriving from `wi`.
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector;
+
+    // Pdf with which `sample` casts a ray along `wi`.
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64;
+
+    // True if every lobe is a delta distribution, which light sampling can never hit.
+    fn is_delta(&self) -> bool;
+
+    // True if there is no surface at all, so that paths and shadow rays carry straight on and the
+    // shape only bounds its medium.
+    fn is_pass_through(&self) -> bool {
+        false
+    }
+
+    // Index of refraction and absorption coefficient of the material's interior, for tracking which
+    // medium a path is in.
+    fn medium(&self) -> (f64, Vector) {
+        (1.0, Vector::zero())
+    }
+
+    // The medium filling the interior of a surface of colour `color`, if the material has one that
+    // scatters light.
+    fn interior(&self, _color: Vector) -> Option<Medium> {
+        None
+    }
+
+    // Contribution of a light sample from direction `wi`, chosen with pdf `light_pdf`, relative to
+    // the light's emission. BSDF sampling picks this material with probability `select`.
+    fn light_weight(&self, s: &Surface, wi: Vector, select: f64, light_pdf: f64) -> Vector {
+        if self.is_delta() {
+            return Vector::zero();
+        }
+
+        let pdf = self.pdf(s, wi) * select;
+        let f = self.evaluate(s, wi);
+        f * (power_heuristic(light_pdf, pdf) / light_pdf)
+    }
+}
+
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+// Two unit vectors that form an orthonormal basis with the unit vector `w`.
+pub fn basis(w: Vector) -> (Vector, Vector) {
+    let u = (if w.x.abs() > 0.1 {
+                Vector::new(0.0, 1.0, 0.0)
+            } else {
+                Vector::new(1.0, 0.0, 0.0)
+            })
+            .cross(w)
+            .norm();
+    (u, w.cross(u))
+}
+
+fn diffuse(Xi: &mut Rng, s: &Surface, cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let nl = if s.norm.dot(s.dir) < 0.0 {
+        s.norm
+    } else {
+        s.norm * -1.0
+    };
+
+    let r1 = 2.0 * f64::consts::PI * Xi.next_f64();
+    let r2 = Xi.next_f64();
+    let r2s = r2.sqrt();
+    let w = nl;
+    let (u, v) = basis(w);
+    let cos = (1.0 - r2).sqrt();
+    let d = (u * r1.cos() * r2s + v * r1.sin() * r2s + w * cos).norm();
+    cast(Vector::one(), Ray::new(s.pos, d), Some(cos * f64::consts::FRAC_1_PI))
+}
+
+// BSDF times cosine for light arriving from `wi`, and the pdf with which `diffuse` samples `wi`.
+fn diffuse_eval(s: &Surface, wi: Vector) -> (f64, f64) {
+    let nl = if s.norm.dot(s.dir) < 0.0 {
+        s.norm
+    } else {
+        s.norm * -1.0
+    };
+
+    let cos = wi.dot(nl);
+    if cos <= 0.0 {
+        (0.0, 0.0)
+    } else {
+        (cos * f64::consts::FRAC_1_PI, cos * f64::consts::FRAC_1_PI)
+    }
+}
+
+// Oren–Nayar's qualitative model, as a factor of the Lambertian BRDF.
+fn oren_nayar_factor(dir: Vector, norm: Vector, wi: Vector, sigma: f64) -> f64 {
+    let nl = if norm.dot(dir) < 0.0 {
+        norm
+    } else {
+        norm * -1.0
+    };
+
+    let wo = dir * -1.0;
+    let cos_i = wi.dot(nl);
+    let cos_o = wo.dot(nl);
+    let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+    let max_cos = if sin_i > 1e-4 && sin_o > 1e-4 {
+        ((wi - nl * cos_i) / sin_i).dot((wo - nl * cos_o) / sin_o).max(0.0)
+    } else {
+        0.0
+    };
+
+    let (sin_alpha, tan_beta) = if cos_i > cos_o {
+        (sin_o, sin_i / cos_i)
+    } else {
+        (sin_i, sin_o / cos_o)
+    };
+
+    let sigma2 = sigma * sigma;
+    let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+    let b = 0.45 * sigma2 / (sigma2 + 0.09);
+    a + b * max_cos * sin_alpha * tan_beta
+}
+
+// Samples the cosine-weighted hemisphere like `diffuse`, and weights by the Oren–Nayar factor.
+fn oren_nayar(Xi: &mut Rng, s: &Surface, sigma: f64, cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    diffuse(Xi, s, &mut |scale, ray, pdf| {
+        cast(scale * oren_nayar_factor(s.dir, s.norm, ray.d, sigma), ray, pdf)
+    })
+}
+
+fn oren_nayar_eval(s: &Surface, wi: Vector, sigma: f64) -> (f64, f64) {
+    let (f, pdf) = diffuse_eval(s, wi);
+    if pdf > 0.0 {
+        (f * oren_nayar_factor(s.dir, s.norm, wi, sigma), pdf)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+fn specular(s: &Surface, cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    cast(Vector::one(), Ray::new(s.pos, s.dir - s.norm * 2.0 * s.norm.dot(s.dir)), None)
+}
+
+fn glossy_refraction(depth: i32,
+                     Xi: &mut Rng,
+                     s: &Surface,
+                     nt: f64,
+                     cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let (pos, dir, norm, nc) = (s.pos, s.dir, s.norm, s.nc);
+    let refl_ray = Ray::new(pos, dir - norm * 2.0 * norm.dot(dir));
+
+    let nl = if norm.dot(dir) < 0.0 {
+        norm
+    } else {
+        norm * -1.0
+    };
+
+    let into = norm.dot(nl) > 0.0;
+    let nnt = if into {
+        nc / nt
+    } else {
+        nt / nc
+    };
+    let ddn = dir.dot(nl);
+    let cos2t = 1.0 - nnt * nnt * (1.0 - ddn * ddn);
+    if cos2t < 0.0 {
+        cast(Vector::one(), refl_ray, None);
+    } else {
+        let tdir = (dir * nnt -
+                    norm *
+                    ((if into {
+                       1.0
+                   } else {
+                       -1.0
+                   }) * (ddn * nnt + cos2t.sqrt())))
+                       .norm();
+        let trans_ray = Ray::new(pos, tdir);
+        let a = nt - nc;
+        let b = nt + nc;
+        let R0 = a * a / (b * b);
+        let c = 1.0 -
+                (if into {
+            -ddn
+        } else {
+            tdir.dot(norm)
+        });
+        let Re = R0 + (1.0 - R0) * c * c * c * c * c;
+        let Tr = 1.0 - Re;
+        let P = 0.25 * 0.5 * Re;
+        let RP = Re / P;
+        let TP = Tr / (1.0 - P);
+        if depth > 2 {
+            if Xi.next_f64() < P {
+                cast(Vector::one() * RP, refl_ray, None);
+            } else {
+                cast(Vector::one() * TP, trans_ray, None);
+            }
+        } else {
+            cast(Vector::one() * Re, refl_ray, None);
+            cast(Vector::one() * Tr, trans_ray, None);
+        }
+    }
+}
+
+fn _refraction(_depth: i32,
+               _Xi: &mut Rng,
+               pos: Vector,
+               dir: Vector,
+               norm: Vector,
+               nc: f64,
+               nt: f64,
+               cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let nl = if norm.dot(dir) < 0.0 {
+        norm
+    } else {
+        norm * -1.0
+    };
+
+    let into = norm.dot(nl) > 0.0;
+    let nnt = if into {
+        nc / nt
+    } else {
+        nt / nc
+    };
+    let ddn = dir.dot(nl);
+    let cos2t = 1.0 - nnt * nnt * (1.0 - ddn * ddn);
+    if cos2t < 0.0 {
+        cast(Vector::one(), Ray::new(pos, dir - norm * 2.0 * norm.dot(dir)), None);
+    } else {
+        let tdir = (dir * nnt -
+                    norm *
+                    ((if into {
+                       1.0
+                   } else {
+                       -1.0
+                   }) * (ddn * nnt + cos2t.sqrt())))
+                       .norm();
+        cast(Vector::one(), Ray::new(pos, tdir), None);
+    }
+}
+
+// Shading frame with `w` along the normal on the side that `dir` arrives from.
+fn frame(dir: Vector, norm: Vector) -> (Vector, Vector, Vector) {
+    let w = if norm.dot(dir) < 0.0 {
+        norm
+    } else {
+        norm * -1.0
+    };
+
+    let (u, v) = basis(w);
+    (u, v, w)
+}
+
+// GGX normal distribution, in a frame whose z axis is the surface normal.
+fn ggx_d(alpha: f64, h: Vector) -> f64 {
+    let a2 = alpha * alpha;
+    let t = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (f64::consts::PI * t * t)
+}
+
+// Smith's Λ function for GGX, from which the masking and shadowing terms are built.
+fn ggx_lambda(alpha: f64, w: Vector) -> f64 {
+    let cos2 = w.z * w.z;
+    if cos2 <= 0.0 {
+        return f64::INFINITY;
+    }
+
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    0.5 * (-1.0 + (1.0 + alpha * alpha * tan2).sqrt())
+}
+
+// Samples a microfacet normal from the distribution of normals visible from `wo` (Heitz 2018).
+fn ggx_sample_visible(alpha: f64, wo: Vector, u1: f64, u2: f64) -> Vector {
+    let vh = Vector::new(alpha * wo.x, alpha * wo.y, wo.z).norm();
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if lensq > 0.0 {
+        Vector::new(-vh.y, vh.x, 0.0) / lensq.sqrt()
+    } else {
+        Vector::new(1.0, 0.0, 0.0)
+    };
+
+    let t2 = vh.cross(t1);
+    let r = u1.sqrt();
+    let phi = 2.0 * f64::consts::PI * u2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+    Vector::new(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).norm()
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction `eta + ik`, per channel.
+fn fresnel_conductor(cos: f64, eta: Vector, k: Vector) -> Vector {
+    let channel = |eta: f64, k: f64| {
+        let cos2 = cos * cos;
+        let sin2 = 1.0 - cos2;
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let t1 = a2b2 + cos2;
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    };
+
+    Vector::new(channel(eta.x, k.x), channel(eta.y, k.y), channel(eta.z, k.z))
+}
+
+fn roughness_to_alpha(roughness: f64) -> f64 {
+    (roughness * roughness).max(1e-4)
+}
+
+fn conductor(Xi: &mut Rng,
+             s: &Surface,
+             roughness: f64,
+             eta: Vector,
+             k: Vector,
+             cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let alpha = roughness_to_alpha(roughness);
+    let (u, v, w) = frame(s.dir, s.norm);
+    let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+    let h = ggx_sample_visible(alpha, wo, Xi.next_f64(), Xi.next_f64());
+    let wi = h * 2.0 * wo.dot(h) - wo;
+    if wi.z <= 0.0 {
+        return;
+    }
+
+    // With visible normal sampling, the BSDF times cosine over the pdf is F * G2 / G1.
+    let lambda_o = ggx_lambda(alpha, wo);
+    let g1 = 1.0 / (1.0 + lambda_o);
+    let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+    let f = fresnel_conductor(wo.dot(h), eta / s.nc, k / s.nc);
+    let pdf = g1 * ggx_d(alpha, h) / (4.0 * wo.z);
+    let d = (u * wi.x + v * wi.y + w * wi.z).norm();
+    cast(f * (g2 / g1), Ray::new(s.pos, d), Some(pdf))
+}
+
+// BSDF times cosine for light arriving from `wi`, and the pdf with which `conductor` samples `wi`.
+fn conductor_eval(s: &Surface,
+                  wi: Vector,
+                  roughness: f64,
+                  eta: Vector,
+                  k: Vector)
+                  -> (Vector, f64) {
+    let alpha = roughness_to_alpha(roughness);
+    let (u, v, w) = frame(s.dir, s.norm);
+    let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+    let wi = Vector::new(wi.dot(u), wi.dot(v), wi.dot(w));
+    if wi.z <= 0.0 || wo.z <= 0.0 {
+        return (Vector::zero(), 0.0);
+    }
+
+    let h = (wo + wi).norm();
+    let d = ggx_d(alpha, h);
+    let lambda_o = ggx_lambda(alpha, wo);
+    let g1 = 1.0 / (1.0 + lambda_o);
+    let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+    let f = fresnel_conductor(wo.dot(h), eta / s.nc, k / s.nc);
+    (f * (d * g2 / (4.0 * wo.z)), g1 * d / (4.0 * wo.z))
+}
+
+// Fresnel reflectance of an interface between dielectrics, for light arriving from the side with
+// index `eta_i`.
+fn fresnel_dielectric(cos_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+    let r_par = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let r_perp = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    0.5 * (r_par * r_par + r_perp * r_perp)
+}
+
+// Refracts `wo` through a microfacet with normal `h`, where `eta` is the ratio of the index of
+// refraction on `wo`'s side to the one on the far side. None on total internal reflection.
+fn refract(wo: Vector, h: Vector, eta: f64) -> Option<Vector> {
+    let cos_i = wo.dot(h);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(wo * -eta + h * (eta * cos_i - cos_t))
+}
+
+// Indices of refraction on the side of the surface that `dir` arrives from and on the far side.
+fn dielectric_sides(dir: Vector, norm: Vector, nc: f64, nt: f64) -> (f64, f64) {
+    if dir.dot(norm) < 0.0 {
+        (nc, nt)
+    } else {
+        (nt, nc)
+    }
+}
+
+// Rough dielectric after Walter et al. 2007, sampled through visible normals. Either the reflected
+// or the transmitted ray is followed, chosen with probability given by the Fresnel term, so that a
+// path never branches.
+fn rough_refraction(Xi: &mut Rng,
+                    s: &Surface,
+                    roughness: f64,
+                    nt: f64,
+                    cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let alpha = roughness_to_alpha(roughness);
+    let (u, v, w) = frame(s.dir, s.norm);
+    let (eta_o, eta_i) = dielectric_sides(s.dir, s.norm, s.nc, nt);
+    let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+    let h = ggx_sample_visible(alpha, wo, Xi.next_f64(), Xi.next_f64());
+    let d = ggx_d(alpha, h);
+    let lambda_o = ggx_lambda(alpha, wo);
+    let g1 = 1.0 / (1.0 + lambda_o);
+    let Re = fresnel_dielectric(wo.dot(h), eta_o, eta_i);
+    let to_world = |wi: Vector| (u * wi.x + v * wi.y + w * wi.z).norm();
+
+    let reflect = |cast: &mut FnMut(Vector, Ray, Option<f64>), prob: f64| {
+        let wi = h * 2.0 * wo.dot(h) - wo;
+        if wi.z > 0.0 {
+            let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+            let pdf = g1 * d / (4.0 * wo.z);
+            cast(Vector::one() * (Re * g2 / (g1 * prob)),
+                 Ray::new(s.pos, to_world(wi)),
+                 Some(pdf * prob));
+        }
+    };
+
+    let transmit = |cast: &mut FnMut(Vector, Ray, Option<f64>), prob: f64| {
+        if let Some(wi) = refract(wo, h, eta_o / eta_i) {
+            if wi.z < 0.0 {
+                let eta = eta_i / eta_o;
+                let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+                let denom = wo.dot(h) + eta * wi.dot(h);
+                let pdf = g1 * wo.dot(h) * d / wo.z * eta * eta * wi.dot(h).abs() / (denom * denom);
+                cast(Vector::one() * ((1.0 - Re) * g2 / (g1 * eta * eta * prob)),
+                     Ray::new(s.pos, to_world(wi)),
+                     Some(pdf * prob));
+            }
+        }
+    };
+
+    if Xi.next_f64() < Re {
+        reflect(cast, Re);
+    } else {
+        transmit(cast, 1.0 - Re);
+    }
+}
+
+// BSDF times cosine for light arriving from `wi`, and the pdf with which `rough_refraction` samples
+// `wi`.
+fn rough_refraction_eval(s: &Surface, wi: Vector, roughness: f64, nt: f64) -> (f64, f64) {
+    let alpha = roughness_to_alpha(roughness);
+    let (u, v, w) = frame(s.dir, s.norm);
+    let (eta_o, eta_i) = dielectric_sides(s.dir, s.norm, s.nc, nt);
+    let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+    let wi = Vector::new(wi.dot(u), wi.dot(v), wi.dot(w));
+    if wo.z <= 0.0 || wi.z == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let reflected = wi.z > 0.0;
+    let eta = if reflected {
+        1.0
+    } else {
+        eta_i / eta_o
+    };
+
+    let h = (wo + wi * eta).norm();
+    let h = if h.z < 0.0 {
+        h * -1.0
+    } else {
+        h
+    };
+
+    if wo.dot(h) <= 0.0 || (!reflected && wi.dot(h) >= 0.0) {
+        return (0.0, 0.0);
+    }
+
+    let d = ggx_d(alpha, h);
+    let lambda_o = ggx_lambda(alpha, wo);
+    let g1 = 1.0 / (1.0 + lambda_o);
+    let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+    let Re = fresnel_dielectric(wo.dot(h), eta_o, eta_i);
+    let (f, pdf, prob) = if reflected {
+        (Re * d * g2 / (4.0 * wo.z), g1 * d / (4.0 * wo.z), Re)
+    } else {
+        let denom = wo.dot(h) + eta * wi.dot(h);
+        let jacobian = wi.dot(h).abs() / (denom * denom);
+        ((1.0 - Re) * d * g2 * wo.dot(h) * jacobian / wo.z,
+         g1 * wo.dot(h) * d / wo.z * eta * eta * jacobian,
+         1.0 - Re)
+    };
+
+    (f, pdf * prob)
+}
+
+// GGX reflection without the Fresnel term: BSDF times cosine, and the pdf of sampling `wi` through
+// visible normals. Both directions are in the shading frame.
+fn ggx_reflection_eval(alpha: f64, wo: Vector, wi: Vector) -> (f64, f64) {
+    let h = (wo + wi).norm();
+    let d = ggx_d(alpha, h);
+    let lambda_o = ggx_lambda(alpha, wo);
+    let g1 = 1.0 / (1.0 + lambda_o);
+    let g2 = 1.0 / (1.0 + lambda_o + ggx_lambda(alpha, wi));
+    (d * g2 / (4.0 * wo.z), g1 * d / (4.0 * wo.z))
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    let m = (1.0 - cos).max(0.0).min(1.0);
+    m * m * m * m * m
+}
+
+// Clearcoat is a second, fixed-roughness specular lobe on top of the base layer.
+const CLEARCOAT_ROUGHNESS: f64 = 0.1;
+
+// The principled material's parameters at one point on a surface.
+struct PrincipledAt {
+    base_color: Vector,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    clearcoat: f64,
+    sheen: f64,
+    transmission: f64,
+}
+
+impl Principled {
+    // Values from textures are clamped to the range from 0 to 1, and a `roughness` given with the
+    // surface takes the place of the material's own.
+    fn at(&self, s: &Surface) -> PrincipledAt {
+        let base_color = self.base_color.at(s);
+        PrincipledAt {
+            base_color: Vector::new(clamp(base_color.x), clamp(base_color.y), clamp(base_color.z)),
+            metallic: clamp(self.metallic.at(s)),
+            roughness: s.roughness.unwrap_or_else(|| clamp(self.roughness.at(s))),
+            specular: clamp(self.specular.at(s)),
+            clearcoat: clamp(self.clearcoat.at(s)),
+            sheen: clamp(self.sheen.at(s)),
+            transmission: clamp(self.transmission.at(s)),
+        }
+    }
+}
+
+// Index of refraction of a dielectric whose reflectance at normal incidence is the principled
+// model's 0.08 * specular.
+fn principled_ior(specular: f64) -> f64 {
+    let r = (0.08 * specular).sqrt().min(0.99);
+    (1.0 + r) / (1.0 - r)
+}
+
+// Probabilities of sampling the diffuse, specular, clearcoat and transmission lobes.
+fn principled_lobes(p: &PrincipledAt) -> [f64; 4] {
+    let transmission = (1.0 - p.metallic) * p.transmission;
+    let weights = [(1.0 - p.metallic) * (1.0 - p.transmission),
+                   1.0 - transmission,
+                   0.25 * p.clearcoat,
+                   transmission];
+    let total = weights.iter().fold(0.0, |sum, &w| sum + w);
+    [weights[0] / total, weights[1] / total, weights[2] / total, weights[3] / total]
+}
+
+// Disney's principled BRDF (Burley 2012) with transmission as in Burley 2015: a retro-reflective
+// diffuse lobe with sheen, a GGX specular lobe that runs from dielectric to metal, a clearcoat lobe
+// and a rough dielectric for transmission. Returns BSDF times cosine for light arriving from `wi`
+// and the pdf with which `principled` samples `wi`.
+fn principled_eval(p: &PrincipledAt, color: Vector, s: &Surface, wi: Vector) -> (Vector, f64) {
+    let (u, v, w) = frame(s.dir, s.norm);
+    let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+    let l = Vector::new(wi.dot(u), wi.dot(v), wi.dot(w));
+    if wo.z <= 0.0 {
+        return (Vector::zero(), 0.0);
+    }
+
+    let lobes = principled_lobes(p);
+    let transmission = (1.0 - p.metallic) * p.transmission;
+    let mut f = Vector::zero();
+    let mut pdf = 0.0;
+    if transmission > 0.0 {
+        let (ft, pt) = rough_refraction_eval(s, wi, p.roughness, principled_ior(p.specular));
+        let tint = if l.z < 0.0 {
+            color
+        } else {
+            Vector::one()
+        };
+
+        f += tint * (ft * transmission);
+        pdf += lobes[3] * pt;
+    }
+
+    if l.z <= 0.0 {
+        return (f, pdf);
+    }
+
+    let cos_d = l.dot((wo + l).norm());
+    let fd90 = 0.5 + 2.0 * p.roughness * cos_d * cos_d;
+    let fd = (1.0 + (fd90 - 1.0) * schlick_weight(l.z)) *
+             (1.0 + (fd90 - 1.0) * schlick_weight(wo.z));
+    let diffuse = (1.0 - p.metallic) * (1.0 - p.transmission);
+    let sheen = Vector::one() * (p.sheen * schlick_weight(cos_d));
+    f += (color * (fd * f64::consts::FRAC_1_PI) + sheen) * (diffuse * l.z);
+    pdf += lobes[0] * l.z * f64::consts::FRAC_1_PI;
+
+    let f0 = Vector::one() * (0.08 * p.specular * (1.0 - p.metallic)) + color * p.metallic;
+    let fs = f0 + (Vector::one() - f0) * schlick_weight(cos_d);
+    let (spec, spec_pdf) = ggx_reflection_eval(roughness_to_alpha(p.roughness), wo, l);
+    f += fs * (spec * (1.0 - transmission));
+    pdf += lobes[1] * spec_pdf;
+
+    if p.clearcoat > 0.0 {
+        let fc = 0.04 + 0.96 * schlick_weight(cos_d);
+        let (coat, coat_pdf) = ggx_reflection_eval(roughness_to_alpha(CLEARCOAT_ROUGHNESS), wo, l);
+        f += Vector::one() * (0.25 * p.clearcoat * fc * coat);
+        pdf += lobes[2] * coat_pdf;
+    }
+
+    (f, pdf)
+}
+
+// Picks one lobe of the principled material, samples a direction from it, and weights the ray by
+// the whole BSDF over the combined pdf of all lobes.
+fn principled(p: &PrincipledAt,
+              color: Vector,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+    let lobes = principled_lobes(p);
+    let r = Xi.next_f64();
+    let mut wi = None;
+    if r < lobes[0] {
+        diffuse(Xi, s, &mut |_, ray, _| wi = Some(ray.d));
+    } else if r < lobes[0] + lobes[1] + lobes[2] {
+        let roughness = if r < lobes[0] + lobes[1] {
+            p.roughness
+        } else {
+            CLEARCOAT_ROUGHNESS
+        };
+
+        let (u, v, w) = frame(s.dir, s.norm);
+        let wo = Vector::new(-s.dir.dot(u), -s.dir.dot(v), -s.dir.dot(w));
+        let h = ggx_sample_visible(roughness_to_alpha(roughness), wo, Xi.next_f64(), Xi.next_f64());
+        let l = h * 2.0 * wo.dot(h) - wo;
+        if l.z > 0.0 {
+            wi = Some((u * l.x + v * l.y + w * l.z).norm());
+        }
+    } else {
+        rough_refraction(Xi,
+                         s,
+                         p.roughness,
+                         principled_ior(p.specular),
+                         &mut |_, ray, _| wi = Some(ray.d));
+    }
+
+    if let Some(wi) = wi {
+        let (f, pdf) = principled_eval(p, color, s, wi);
+        if pdf > 0.0 {
+            cast(f / pdf, Ray::new(s.pos, wi), Some(pdf));
+        }
+    }
+}
+
+// Fraction of light that a dielectric coat lets through to the base and back out again.
+fn coat_transmission(s: &Surface, wi: Vector, ior: f64) -> f64 {
+    let cos_o = s.dir.dot(s.norm).abs();
+    let cos_i = wi.dot(s.norm).abs();
+    (1.0 - fresnel_dielectric(cos_o, s.nc, ior)) * (1.0 - fresnel_dielectric(cos_i, s.nc, ior))
+}
+
+// Probability of following the coat rather than the base: the coat's reflectance at the macro
+// surface normal.
+fn coat_probability(s: &Surface, ior: f64) -> f64 {
+    fresnel_dielectric(s.dir.dot(s.norm).abs(), s.nc, ior)
+}
+
+struct Diffuse;
+
+impl Bsdf for Diffuse {
+    fn sample(&self,
+              _depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        diffuse(Xi, s, &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        s.color * diffuse_eval(s, wi).0
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        diffuse_eval(s, wi).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+// `sigma` is in degrees.
+struct OrenNayar {
+    sigma: Param<f64>,
+}
+
+impl Bsdf for OrenNayar {
+    fn sample(&self,
+              _depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        oren_nayar(Xi,
+                   s,
+                   self.sigma.at(s).to_radians(),
+                   &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        s.color * oren_nayar_eval(s, wi, self.sigma.at(s).to_radians()).0
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        oren_nayar_eval(s, wi, self.sigma.at(s).to_radians()).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+struct Specular;
+
+impl Bsdf for Specular {
+    fn sample(&self,
+              _depth: i32,
+              _Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        specular(s, &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, _s: &Surface, _wi: Vector) -> Vector {
+        Vector::zero()
+    }
+
+    fn pdf(&self, _s: &Surface, _wi: Vector) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+// The boundary of a medium, which leaves rays as they are. The integrator steps through it without
+// counting a bounce, and shadow rays pass through it too.
+struct Null;
+
+impl Bsdf for Null {
+    fn sample(&self,
+              _depth: i32,
+              _Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        cast(Vector::one(), Ray::new(s.pos, s.dir), None)
+    }
+
+    fn evaluate(&self, _s: &Surface, _wi: Vector) -> Vector {
+        Vector::zero()
+    }
+
+    fn pdf(&self, _s: &Surface, _wi: Vector) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn is_pass_through(&self) -> bool {
+        true
+    }
+}
+
+struct Dielectric {
+    ior: f64,
+    absorption: Vector,
+}
+
+impl Bsdf for Dielectric {
+    fn sample(&self,
+              depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        glossy_refraction(depth,
+                          Xi,
+                          s,
+                          self.ior,
+                          &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, _s: &Surface, _wi: Vector) -> Vector {
+        Vector::zero()
+    }
+
+    fn pdf(&self, _s: &Surface, _wi: Vector) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn medium(&self) -> (f64, Vector) {
+        (self.ior, self.absorption)
+    }
+}
+
+// Chiang, Kutz and Burley's fit for the single-scattering albedo that makes a thick slab of
+// medium reflect a fraction `albedo` of the light entering it.
+fn single_scattering_albedo(albedo: f64) -> f64 {
+    let a = albedo.max(0.0).min(1.0);
+    1.0 - (-5.09406 * a + 2.61188 * a * a - 4.31805 * a * a * a).exp()
+}
+
+// A smooth dielectric boundary, as for glass, over a medium in which light takes a random walk,
+// scattering equally in every direction. The colour tints nothing at the boundary but sets the
+// medium's albedo, so a thick object comes out roughly the shape's colour.
+struct Subsurface {
+    ior: f64,
+    mfp: Vector,
+}
+
+impl Bsdf for Subsurface {
+    fn sample(&self,
+              depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        glossy_refraction(depth, Xi, s, self.ior, cast)
+    }
+
+    fn evaluate(&self, _s: &Surface, _wi: Vector) -> Vector {
+        Vector::zero()
+    }
+
+    fn pdf(&self, _s: &Surface, _wi: Vector) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn medium(&self) -> (f64, Vector) {
+        (self.ior, Vector::zero())
+    }
+
+    fn interior(&self, color: Vector) -> Option<Medium> {
+        let extinction = Vector::new(1.0 / self.mfp.x, 1.0 / self.mfp.y, 1.0 / self.mfp.z);
+        let albedo = Vector::new(single_scattering_albedo(color.x),
+                                 single_scattering_albedo(color.y),
+                                 single_scattering_albedo(color.z));
+        Some(Medium {
+            absorption: extinction * (Vector::one() - albedo),
+            scattering: extinction * albedo,
+            g: 0.0,
+            grid: None,
+        })
+    }
+}
+
+struct Conductor {
+    roughness: Param<f64>,
+    eta: Param<Vector>,
+    k: Param<Vector>,
+}
+
+impl Conductor {
+    fn roughness(&self, s: &Surface) -> f64 {
+        s.roughness.unwrap_or_else(|| self.roughness.at(s))
+    }
+}
+
+impl Bsdf for Conductor {
+    fn sample(&self,
+              _depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        conductor(Xi,
+                  s,
+                  self.roughness(s),
+                  self.eta.at(s),
+                  self.k.at(s),
+                  &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        s.color * conductor_eval(s, wi, self.roughness(s), self.eta.at(s), self.k.at(s)).0
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        conductor_eval(s, wi, self.roughness(s), self.eta.at(s), self.k.at(s)).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+struct RoughDielectric {
+    roughness: f64,
+    ior: f64,
+    absorption: Vector,
+}
+
+impl Bsdf for RoughDielectric {
+    fn sample(&self,
+              _depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        rough_refraction(Xi,
+                         s,
+                         s.roughness.unwrap_or(self.roughness),
+                         self.ior,
+                         &mut |scale, ray, pdf| cast(s.color * scale, ray, pdf))
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        let roughness = s.roughness.unwrap_or(self.roughness);
+        s.color * rough_refraction_eval(s, wi, roughness, self.ior).0
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        let roughness = s.roughness.unwrap_or(self.roughness);
+        rough_refraction_eval(s, wi, roughness, self.ior).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn medium(&self) -> (f64, Vector) {
+        (self.ior, self.absorption)
+    }
+}
+
+// The principled material's base colour is its own `base_color` times the surface colour, and it
+// tints each lobe itself.
+impl Bsdf for Principled {
+    fn sample(&self,
+              _depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        let p = self.at(s);
+        principled(&p, s.color * p.base_color, Xi, s, cast)
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        let p = self.at(s);
+        principled_eval(&p, s.color * p.base_color, s, wi).0
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        principled_eval(&self.at(s), Vector::one(), s, wi).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    // The interior's index of refraction, which has to be the same all over, comes from the
+    // default `specular` of 0.5 when a texture gives `specular`.
+    fn medium(&self) -> (f64, Vector) {
+        let specular = match self.specular {
+            Param::Value(specular) => specular,
+            Param::Texture(_) => 0.5,
+        };
+
+        match self.transmission {
+            Param::Value(transmission) if transmission <= 0.0 => (1.0, Vector::zero()),
+            _ => (principled_ior(specular), Vector::zero()),
+        }
+    }
+}
+
+// A dielectric coat over a base material. The coat reflects like a conductor with no absorption;
+// the base sees whatever the coat transmits, so it fades out at grazing angles. One of the two is
+// followed, chosen by the coat's reflectance.
+struct Coat {
+    ior: Param<f64>,
+    roughness: Param<f64>,
+    base: Box<Bsdf>,
+}
+
+impl Coat {
+    fn eval(&self, s: &Surface, wi: Vector, ior: f64) -> (Vector, f64) {
+        conductor_eval(s, wi, self.roughness.at(s), Vector::one() * ior, Vector::zero())
+    }
+}
+
+impl Bsdf for Coat {
+    fn sample(&self,
+              depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        let ior = self.ior.at(s);
+        let prob = coat_probability(s, ior);
+        if Xi.next_f64() < prob {
+            conductor(Xi,
+                      s,
+                      self.roughness.at(s),
+                      Vector::one() * ior,
+                      Vector::zero(),
+                      &mut |scale, ray, pdf| cast(scale / prob, ray, pdf.map(|pdf| pdf * prob)));
+        } else {
+            self.base.sample(depth, Xi, s, &mut |scale, ray, pdf| {
+                let weight = coat_transmission(s, ray.d, ior);
+                cast(scale * (weight / (1.0 - prob)),
+                     ray,
+                     pdf.map(|pdf| pdf * (1.0 - prob)))
+            });
+        }
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        let ior = self.ior.at(s);
+        let base = self.base.evaluate(s, wi);
+        self.eval(s, wi, ior).0 + base * coat_transmission(s, wi, ior)
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        let ior = self.ior.at(s);
+        let prob = coat_probability(s, ior);
+        let base = self.base.pdf(s, wi);
+        self.eval(s, wi, ior).1 * prob + base * (1.0 - prob)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn medium(&self) -> (f64, Vector) {
+        self.base.medium()
+    }
+
+    fn interior(&self, color: Vector) -> Option<Medium> {
+        self.base.interior(color)
+    }
+
+    // The coat and the base are separate estimators, each weighted against light sampling with its
+    // own pdf.
+    fn light_weight(&self, s: &Surface, wi: Vector, select: f64, light_pdf: f64) -> Vector {
+        let ior = self.ior.at(s);
+        let prob = coat_probability(s, ior);
+        let (f, pdf) = self.eval(s, wi, ior);
+        let base = self.base.light_weight(s, wi, select * (1.0 - prob), light_pdf);
+        f * (power_heuristic(light_pdf, pdf * select * prob) / light_pdf) +
+        base * coat_transmission(s, wi, ior)
+    }
+}
+
+// Follows one of two materials, chosen by its weight, so that nested mixes still cast one ray per
+// bounce. `factor` is the weight of `b`.
+struct Mix {
+    factor: Param<f64>,
+    a: Box<Bsdf>,
+    b: Box<Bsdf>,
+}
+
+impl Bsdf for Mix {
+    fn sample(&self,
+              depth: i32,
+              Xi: &mut Rng,
+              s: &Surface,
+              cast: &mut FnMut(Vector, Ray, Option<f64>)) {
+        // The weight and the probability of the choice cancel.
+        let factor = clamp(self.factor.at(s));
+        let (bsdf, prob) = if Xi.next_f64() < factor {
+            (&self.b, factor)
+        } else {
+            (&self.a, 1.0 - factor)
+        };
+
+        bsdf.sample(depth,
+                    Xi,
+                    s,
+                    &mut |scale, ray, pdf| cast(scale, ray, pdf.map(|pdf| pdf * prob)));
+    }
+
+    fn evaluate(&self, s: &Surface, wi: Vector) -> Vector {
+        let factor = clamp(self.factor.at(s));
+        self.a.evaluate(s, wi) * (1.0 - factor) + self.b.evaluate(s, wi) * factor
+    }
+
+    fn pdf(&self, s: &Surface, wi: Vector) -> f64 {
+        let factor = clamp(self.factor.at(s));
+        self.a.pdf(s, wi) * (1.0 - factor) + self.b.pdf(s, wi) * factor
+    }
+
+    fn is_delta(&self) -> bool {
+        self.a.is_delta() && self.b.is_delta()
+    }
+
+    fn medium(&self) -> (f64, Vector) {
+        match self.b.medium() {
+            (ior, _) if ior == 1.0 => self.a.medium(),
+            inside => inside,
+        }
+    }
+
+    fn interior(&self, color: Vector) -> Option<Medium> {
+        self.b.interior(color).or_else(|| self.a.interior(color))
+    }
+
+    // Each material is its own estimator, weighted against light sampling with its own pdf.
+    fn light_weight(&self, s: &Surface, wi: Vector, select: f64, light_pdf: f64) -> Vector {
+        let factor = clamp(self.factor.at(s));
+        self.a.light_weight(s, wi, select * (1.0 - factor), light_pdf) * (1.0 - factor) +
+        self.b.light_weight(s, wi, select * factor, light_pdf) * factor
+    }
+}
+
+impl Refl {
+    pub fn bsdf(&self) -> Box<Bsdf> {
+        match self {
+            &Refl::Diff => Box::new(Diffuse),
+            &Refl::OrenNayar(sigma) => Box::new(OrenNayar { sigma: sigma }),
+            &Refl::Spec => Box::new(Specular),
+            &Refl::Null => Box::new(Null),
+            &Refl::Subsurface(ior, mfp) => {
+                Box::new(Subsurface {
+                    ior: ior,
+                    mfp: mfp,
+                })
+            }
+            &Refl::Refr(ior, absorption) => {
+                Box::new(Dielectric {
+                    ior: ior,
+                    absorption: absorption,
+                })
+            }
+            &Refl::Metal(roughness, eta, k) => {
+                Box::new(Conductor {
+                    roughness: roughness,
+                    eta: eta,
+                    k: k,
+                })
+            }
+            &Refl::RoughRefr(roughness, ior, absorption) => {
+                Box::new(RoughDielectric {
+                    roughness: roughness,
+                    ior: ior,
+                    absorption: absorption,
+                })
+            }
+            &Refl::Principled(ref p) => Box::new(*p),
+            &Refl::Coat(ior, roughness, ref base) => {
+                Box::new(Coat {
+                    ior: ior,
+                    roughness: roughness,
+                    base: base.bsdf(),
+                })
+            }
+            &Refl::Mix(factor, ref a, ref b) => {
+                Box::new(Mix {
+                    factor: factor,
+                    a: a.bsdf(),
+                    b: b.bsdf(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{Param, Pattern, Principled, Procedural, Refl, Texture, Vector};
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::f64;
+    use super::Surface;
+
+    fn materials() -> Vec<(&'static str, Refl)> {
+        let metal = || {
+            Refl::Metal(Param::Value(0.4),
+                        Param::Value(Vector::new(0.2, 0.9, 1.1)),
+                        Param::Value(Vector::new(3.9, 2.4, 2.2)))
+        };
+
+        let principled = Principled {
+            base_color: Param::Value(Vector::new(0.8, 0.5, 0.3)),
+            metallic: Param::Value(0.3),
+            roughness: Param::Value(0.4),
+            specular: Param::Value(0.5),
+            clearcoat: Param::Value(0.5),
+            sheen: Param::Value(0.5),
+            transmission: Param::Value(0.3),
+        };
+
+        vec![("diff", Refl::Diff),
+             ("oren-nayar", Refl::OrenNayar(Param::Value(30.0))),
+             ("metal", metal()),
+             ("rough glass", Refl::RoughRefr(0.4, 1.5, Vector::zero())),
+             ("principled", Refl::Principled(principled)),
+             ("textured principled",
+              Refl::Principled(Principled {
+                  metallic: Param::Texture(0),
+                  roughness: Param::Texture(0),
+                  ..principled
+              })),
+             ("coat", Refl::Coat(Param::Value(1.5), Param::Value(0.3), Box::new(Refl::Diff))),
+             ("mix", Refl::Mix(Param::Texture(0), Box::new(Refl::Diff), Box::new(metal()))),
+             ("spec", Refl::Spec),
+             ("glass", Refl::Refr(1.5, Vector::zero()))]
+    }
+
+    fn textures() -> Vec<Texture> {
+        vec![Texture::Procedural(Procedural {
+                 pattern: Pattern::Checker,
+                 uv: true,
+                 scale: 4.0,
+                 a: Vector::one() * 0.25,
+                 b: Vector::one() * 0.75,
+             })]
+    }
+
+    fn surface(dir: Vector, textures: &[Texture]) -> Surface {
+        Surface {
+            pos: Vector::zero(),
+            dir: dir,
+            norm: Vector::new(0.0, 0.0, 1.0),
+            nc: 1.0,
+            color: Vector::new(0.9, 0.6, 0.3),
+            roughness: None,
+            uv: (0.3, 0.6),
+            textures: textures,
+        }
+    }
+
+    fn direction(xi: &mut StdRng) -> Vector {
+        let z = 1.0 - 2.0 * xi.next_f64();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * f64::consts::PI * xi.next_f64();
+        Vector::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-6 * a.abs().max(b.abs()).max(1.0)
+    }
+
+    fn close_vector(a: Vector, b: Vector) -> bool {
+        close(a.x, b.x) && close(a.y, b.y) && close(a.z, b.z)
+    }
+
+    // Every ray that `sample` casts through a lobe with a pdf is weighted by the BSDF times cosine
+    // over the pdf, with the same pdf that `pdf` gives. Coat and Mix weight each ray by the lobe it
+    // came from alone, so for them this only holds on average. Delta materials give nothing to
+    // light sampling.
+    #[test]
+    fn sample_matches_evaluate() {
+        let mut xi = StdRng::from_seed(&[1][..]);
+        let textures = textures();
+        for &(name, ref refl) in &materials() {
+            let bsdf = refl.bsdf();
+            for _ in 0..16 {
+                let s = surface(direction(&mut xi), &textures);
+                if bsdf.is_delta() {
+                    let wi = direction(&mut xi);
+                    assert!(bsdf.pdf(&s, wi) == 0.0, "{}: a pdf for a delta lobe", name);
+                    let mut total = Vector::zero();
+                    bsdf.sample(1, &mut xi, &s, &mut |weight, _, pdf| {
+                        assert!(pdf.is_none(), "{}: a pdf for a delta lobe", name);
+                        total += weight;
+                    });
+
+                    assert!(total.x.max(total.y).max(total.z) <= 1.0 + 1e-9,
+                            "{}: more light out than in",
+                            name);
+                    continue;
+                }
+
+                let whole = match refl {
+                    &Refl::Coat(..) | &Refl::Mix(..) => false,
+                    _ => true,
+                };
+
+                let n = 10000;
+                let mut error = Vector::zero();
+                for _ in 0..n {
+                    bsdf.sample(1, &mut xi, &s, &mut |weight, ray, pdf| {
+                        let pdf = pdf.expect(name);
+                        let f = bsdf.evaluate(&s, ray.d);
+                        if whole {
+                            assert!(close(bsdf.pdf(&s, ray.d), pdf), "{}: pdf differs", name);
+                            assert!(close_vector(weight * pdf, f), "{}: weight differs", name);
+                        }
+
+                        error += (weight - f / bsdf.pdf(&s, ray.d)) / n as f64;
+                    });
+                }
+
+                assert!(error.x.abs().max(error.y.abs()).max(error.z.abs()) < 0.01,
+                        "{}: weights off by {} {} {} on average",
+                        name,
+                        error.x,
+                        error.y,
+                        error.z);
+            }
+        }
+    }
+
+    // Reflection is the same with the directions swapped, once the cosine with the direction the
+    // light comes from is divided out. Transmission is not, as radiance is squeezed into a smaller
+    // solid angle on the denser side.
+    #[test]
+    fn reflection_is_reciprocal() {
+        let mut xi = StdRng::from_seed(&[2][..]);
+        let textures = textures();
+        for &(name, ref refl) in &materials() {
+            let bsdf = refl.bsdf();
+            if bsdf.is_delta() {
+                continue;
+            }
+
+            for _ in 0..256 {
+                let mut wo = direction(&mut xi);
+                let mut wi = direction(&mut xi);
+                wo.z = wo.z.abs();
+                wi.z = wi.z.abs();
+                let there = bsdf.evaluate(&surface(wo * -1.0, &textures), wi) / wi.z;
+                let back = bsdf.evaluate(&surface(wi * -1.0, &textures), wo) / wo.z;
+                assert!(close_vector(there, back), "{}: not reciprocal", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use api::{Ray, Shape, Vector};
+use scene::Hit;
+use std::f64;
+
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vector,
+    pub max: Vector,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vector::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Vector::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn point(p: Vector) -> Self {
+        Aabb { min: p, max: p }
+    }
+
+    pub fn union(self, other: Aabb) -> Self {
+        Aabb {
+            min: Vector::new(self.min.x.min(other.min.x),
+                             self.min.y.min(other.min.y),
+                             self.min.z.min(other.min.z)),
+            max: Vector::new(self.max.x.max(other.max.x),
+                             self.max.y.max(other.max.y),
+                             self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn centroid(&self) -> Vector {
+        (self.min + self.max) * 0.5
+    }
+
+    fn is_finite(&self) -> bool {
+        [self.min.x, self.min.y, self.min.z, self.max.x, self.max.y, self.max.z]
+            .iter()
+            .all(|x| x.is_finite())
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        }
+    }
+
+    // Slab test against a ray, given the reciprocal of its direction.
+    fn hit(&self, o: Vector, inv_d: Vector, t_max: f64) -> bool {
+        let mut t0 = 0.0f64;
+        let mut t1 = t_max;
+        for &(min, max, o, inv_d) in &[(self.min.x, self.max.x, o.x, inv_d.x),
+                                      (self.min.y, self.max.y, o.y, inv_d.y),
+                                      (self.min.z, self.max.z, o.z, inv_d.z)] {
+            let near = (min - o) * inv_d;
+            let far = (max - o) * inv_d;
+            t0 = t0.max(near.min(far));
+            t1 = t1.min(near.max(far));
+        }
+
+        t0 <= t1
+    }
+}
+
+fn axis(v: Vector, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+impl Shape {
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            &Shape::Sphere(ref s) => {
+                let r = Vector::new(s.rad, s.rad, s.rad);
+                Aabb {
+                    min: s.p - r,
+                    max: s.p + r,
+                }
+            }
+            &Shape::Triangle(ref t) => {
+                Aabb::point(t.v0).union(Aabb::point(t.v1)).union(Aabb::point(t.v2))
+            }
+            &Shape::Mesh(ref m) => {
+                m.vertices.iter().fold(Aabb::empty(), |b, &v| b.union(Aabb::point(v)))
+            }
+        }
+    }
+}
+
+// A single leaf entry: a whole shape, or one face of a mesh so that large meshes are split up
+// across the tree like any other geometry.
+#[derive(Copy, Clone)]
+enum Prim {
+    Shape(usize),
+    Face(usize, usize),
+}
+
+impl Prim {
+    fn shape(&self) -> usize {
+        match *self {
+            Prim::Shape(i) | Prim::Face(i, _) => i,
+        }
+    }
+
+    fn intersect<'a>(&self, scene: &'a [Shape], ray: Ray) -> Option<(f64, Hit<'a>)> {
+        match *self {
+            Prim::Shape(i) => scene[i].intersect(ray),
+            Prim::Face(i, face) => {
+                match scene[i] {
+                    Shape::Mesh(ref mesh) => mesh.intersect_face(face, ray),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+struct Item {
+    prim: Prim,
+    bounds: Aabb,
+    centroid: Vector,
+}
+
+// Nodes are stored depth first: an interior node's first child directly follows it and `offset`
+// is the index of its second child. A leaf has `count > 0` and covers
+// `prims[offset..offset + count]`.
+struct Node {
+    bounds: Aabb,
+    offset: usize,
+    count: usize,
+    axis: usize,
+}
+
+const BINS: usize = 16;
+const MAX_DEPTH: usize = 64;
+const MAX_LEAF: usize = 4;
+const TRAVERSAL_COST: f64 = 0.125;
+
+pub struct Bvh {
+    nodes: Vec<Node>,
+    prims: Vec<Prim>,
+}
+
+impl Bvh {
+    pub fn new(scene: &[Shape]) -> Self {
+        let mut items = Vec::new();
+        for (i, shape) in scene.iter().enumerate() {
+            match shape {
+                &Shape::Mesh(ref mesh) => {
+                    for (face, &(i0, i1, i2)) in mesh.faces.iter().enumerate() {
+                        let bounds = Aabb::point(mesh.vertices[i0])
+                                         .union(Aabb::point(mesh.vertices[i1]))
+                                         .union(Aabb::point(mesh.vertices[i2]));
+                        items.push(Item {
+                            prim: Prim::Face(i, face),
+                            bounds: bounds,
+                            centroid: bounds.centroid(),
+                        });
+                    }
+                }
+                _ => {
+                    let bounds = shape.bounds();
+                    items.push(Item {
+                        prim: Prim::Shape(i),
+                        bounds: bounds,
+                        centroid: bounds.centroid(),
+                    });
+                }
+            }
+        }
+
+        // A shape without finite bounds has no place in the tree, and its centroid would break the
+        // median split's ordering.
+        items.retain(|item| item.bounds.is_finite());
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            prims: Vec::with_capacity(items.len()),
+        };
+
+        if !items.is_empty() {
+            bvh.build(&mut items, 0);
+        }
+
+        bvh
+    }
+
+    fn leaf(&mut self, index: usize, items: &[Item]) {
+        self.nodes[index].offset = self.prims.len();
+        self.nodes[index].count = items.len();
+        self.prims.extend(items.iter().map(|item| item.prim));
+    }
+
+    fn build(&mut self, items: &mut [Item], depth: usize) {
+        let bounds = items.iter().fold(Aabb::empty(), |b, item| b.union(item.bounds));
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            bounds: bounds,
+            offset: 0,
+            count: 0,
+            axis: 0,
+        });
+
+        if items.len() <= 1 || depth + 1 >= MAX_DEPTH {
+            return self.leaf(index, items);
+        }
+
+        let centroids = items.iter()
+                             .fold(Aabb::empty(), |b, item| b.union(Aabb::point(item.centroid)));
+        let extent = centroids.max - centroids.min;
+        let split_axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        let lo = axis(centroids.min, split_axis);
+        let hi = axis(centroids.max, split_axis);
+        if hi <= lo {
+            return self.leaf(index, items);
+        }
+
+        // Binned surface area heuristic: cost of splitting between each pair of adjacent bins.
+        let bin = |item: &Item| {
+            let x = (axis(item.centroid, split_axis) - lo) / (hi - lo);
+            ((x * BINS as f64) as usize).min(BINS - 1)
+        };
+
+        let mut counts = [0usize; BINS];
+        let mut bin_bounds = [Aabb::empty(); BINS];
+        for item in items.iter() {
+            let b = bin(item);
+            counts[b] += 1;
+            bin_bounds[b] = bin_bounds[b].union(item.bounds);
+        }
+
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        for split in 1..BINS {
+            let mut left = (Aabb::empty(), 0);
+            let mut right = (Aabb::empty(), 0);
+            for b in 0..BINS {
+                let side = if b < split {
+                    &mut left
+                } else {
+                    &mut right
+                };
+
+                *side = (side.0.union(bin_bounds[b]), side.1 + counts[b]);
+            }
+
+            if left.1 == 0 || right.1 == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST +
+                       (left.0.surface_area() * left.1 as f64 +
+                        right.0.surface_area() * right.1 as f64) /
+                       bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let mid = if best_split == 0 {
+            // Every centroid fell in one bin; fall back to a median split.
+            items.sort_by(|a, b| {
+                axis(a.centroid, split_axis)
+                    .partial_cmp(&axis(b.centroid, split_axis))
+                    .unwrap()
+            });
+            items.len() / 2
+        } else {
+            if items.len() <= MAX_LEAF && best_cost >= items.len() as f64 {
+                return self.leaf(index, items);
+            }
+
+            let mut mid = 0;
+            for i in 0..items.len() {
+                if bin(&items[i]) < best_split {
+                    items.swap(i, mid);
+                    mid += 1;
+                }
+            }
+
+            mid
+        };
+
+        let (left, right) = items.split_at_mut(mid);
+        self.build(left, depth + 1);
+        let second = self.nodes.len();
+        self.build(right, depth + 1);
+        self.nodes[index].offset = second;
+        self.nodes[index].axis = split_axis;
+    }
+
+    // Returns the distance to the nearest hit, the index of the shape that was hit, and the hit.
+    pub fn intersect<'a>(&self, scene: &'a [Shape], ray: Ray) -> Option<(f64, usize, Hit<'a>)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_d = Vector::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let mut nearest = None;
+        let mut t_max = f64::INFINITY;
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut sp = 0;
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index];
+            if node.bounds.hit(ray.o, inv_d, t_max) {
+                if node.count > 0 {
+                    for prim in &self.prims[node.offset..node.offset + node.count] {
+                        if let Some((t, hit)) = prim.intersect(scene, ray) {
+                            if t < t_max {
+                                t_max = t;
+                                nearest = Some((t, prim.shape(), hit));
+                            }
+                        }
+                    }
+                } else {
+                    // Visit the child nearer the ray origin first, so that hits there can prune
+                    // the other one.
+                    if axis(inv_d, node.axis) < 0.0 {
+                        stack[sp] = index + 1;
+                        index = node.offset;
+                    } else {
+                        stack[sp] = node.offset;
+                        index = index + 1;
+                    }
+
+                    sp += 1;
+                    continue;
+                }
+            }
+
+            if sp == 0 {
+                break;
+            }
+
+            sp -= 1;
+            index = stack[sp];
+        }
+
+        nearest
+    }
+}
+
+// The nearest hit by testing every shape, as the renderer did before it had a BVH. The tests and
+// the benchmark check the tree against it.
+pub fn intersect_linear<'a>(scene: &'a [Shape], ray: Ray) -> Option<(f64, usize, Hit<'a>)> {
+    let mut nearest: Option<(f64, usize, Hit)> = None;
+    for (i, shape) in scene.iter().enumerate() {
+        if let Some((t, hit)) = shape.intersect(ray) {
+            if nearest.as_ref().map_or(true, |&(t0, _, _)| t < t0) {
+                nearest = Some((t, i, hit));
+            }
+        }
+    }
+
+    nearest
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{Mesh, Ray, Refl, Shape, Sphere, Triangle, Vector};
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::f64;
+    use super::{Bvh, intersect_linear};
+
+    fn point(xi: &mut StdRng, size: f64) -> Vector {
+        Vector::new(xi.gen_range(-size, size),
+                    xi.gen_range(-size, size),
+                    xi.gen_range(-size, size))
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut xi = StdRng::from_seed(&[1][..]);
+        let white = Vector::new(1.0, 1.0, 1.0);
+        let mut scene = Vec::new();
+        for _ in 0..300 {
+            let p = point(&mut xi, 50.0);
+            scene.push(Shape::Sphere(Sphere::new(xi.gen_range(0.1, 3.0),
+                                                 p,
+                                                 Vector::zero(),
+                                                 white,
+                                                 Refl::Diff)));
+        }
+
+        for _ in 0..300 {
+            let v0 = point(&mut xi, 50.0);
+            let v1 = v0 + point(&mut xi, 4.0);
+            let v2 = v0 + point(&mut xi, 4.0);
+            let triangle = Triangle::new(v0, v1, v2, Vector::zero(), white, Refl::Diff);
+            scene.push(Shape::Triangle(triangle));
+        }
+
+        let vertices = (0..90).map(|_| point(&mut xi, 50.0)).collect();
+        let faces = (0..30).map(|i| (3 * i, 3 * i + 1, 3 * i + 2)).collect();
+        scene.push(Shape::Mesh(Mesh::new(vertices,
+                                         Vec::new(),
+                                         Vec::new(),
+                                         faces,
+                                         Vector::zero(),
+                                         white,
+                                         Refl::Diff)));
+
+        let bvh = Bvh::new(&scene);
+        for _ in 0..20000 {
+            let o = point(&mut xi, 80.0);
+            let ray = Ray::new(o, (point(&mut xi, 50.0) - o).norm());
+            let expected = intersect_linear(&scene, ray).map(|(t, i, _)| (t, i));
+            let actual = bvh.intersect(&scene, ray).map(|(t, i, _)| (t, i));
+            match (expected, actual) {
+                (None, None) => {}
+                (Some((t0, i0)), Some((t1, i1))) => {
+                    assert!((t0 - t1).abs() < 1e-9, "distance {} from a scan but {}", t0, t1);
+                    assert!(i0 == i1 || t0 == t1, "shape {} from a scan but {}", i0, i1);
+                }
+                _ => panic!("a scan gives {:?} but the BVH {:?}", expected, actual),
+            }
+        }
+    }
+
+    #[test]
+    fn infinite_sphere() {
+        // Its bounds spoil the cost of every split, which left a median split to order its
+        // centroid, which is not a number.
+        let white = Vector::new(1.0, 1.0, 1.0);
+        let mut scene = vec![Shape::Sphere(Sphere::new(f64::INFINITY,
+                                                       Vector::zero(),
+                                                       Vector::zero(),
+                                                       white,
+                                                       Refl::Diff))];
+        for i in 0..8 {
+            let p = Vector::new(i as f64 * 10.0, 0.0, 0.0);
+            scene.push(Shape::Sphere(Sphere::new(1.0, p, Vector::zero(), white, Refl::Diff)));
+        }
+
+        let bvh = Bvh::new(&scene);
+        let ray = Ray::new(Vector::new(30.0, 0.0, -10.0), Vector::new(0.0, 0.0, 1.0));
+        assert_eq!(bvh.intersect(&scene, ray).map(|(_, i, _)| i), Some(4));
+    }
+}
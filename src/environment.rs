@@ -0,0 +1,153 @@
+#![allow(non_snake_case)]
+use api::{EnvironmentMap, Image, Vector};
+use rand::Rng;
+use std::f64;
+
+// Texture coordinates of a direction in an equirectangular image turned by `rotation` about the y
+// axis, laid out like the spherical mapping of a sphere.
+fn uv(dir: Vector, rotation: f64) -> (f64, f64) {
+    let u = 0.5 + (dir.z.atan2(dir.x) - rotation) / (2.0 * f64::consts::PI);
+    (u - u.floor(), 0.5 + dir.y.max(-1.0).min(1.0).asin() / f64::consts::PI)
+}
+
+fn dir(uv: (f64, f64), rotation: f64) -> Vector {
+    let phi = (uv.0 - 0.5) * 2.0 * f64::consts::PI + rotation;
+    let lat = (uv.1 - 0.5) * f64::consts::PI;
+    Vector::new(lat.cos() * phi.cos(), lat.sin(), lat.cos() * phi.sin())
+}
+
+// Tabulates the light arriving from each direction as an unrotated equirectangular image.
+pub fn bake<F: Fn(Vector) -> Vector>(width: usize, height: usize, radiance: F) -> Image {
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let uv = ((x as f64 + 0.5) / width as f64, 1.0 - (y as f64 + 0.5) / height as f64);
+            let l = radiance(dir(uv, 0.0));
+            pixels.push(l.x as f32);
+            pixels.push(l.y as f32);
+            pixels.push(l.z as f32);
+        }
+    }
+
+    Image {
+        width: width,
+        height: height,
+        pixels: pixels,
+    }
+}
+
+impl EnvironmentMap {
+    pub fn radiance(&self, dir: Vector) -> Vector {
+        self.image.lookup_equirect(uv(dir, self.rotation)) * self.intensity
+    }
+}
+
+// Index of the interval of a cumulative distribution that `x` falls in.
+fn find(cdf: &[f64], x: f64) -> usize {
+    let mut lo = 0;
+    let mut hi = cdf.len() - 1;
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] <= x {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    lo
+}
+
+fn cumulative(weights: &[f64]) -> Vec<f64> {
+    let total = weights.iter().fold(0.0, |sum, &w| sum + w);
+    let mut cdf = Vec::with_capacity(weights.len() + 1);
+    let mut sum = 0.0;
+    cdf.push(0.0);
+    for &w in weights {
+        sum += if total > 0.0 {
+            w / total
+        } else {
+            1.0 / weights.len() as f64
+        };
+
+        cdf.push(sum);
+    }
+
+    cdf
+}
+
+// Picks pixels of an equirectangular image in proportion to their luminance times the solid angle
+// that they cover: first a row, then a pixel within the row.
+pub struct Sampler {
+    rotation: f64,
+    rows: Vec<f64>,
+    columns: Vec<Vec<f64>>,
+}
+
+impl Sampler {
+    pub fn new(image: &Image, rotation: f64) -> Option<Self> {
+        let mut rows = Vec::with_capacity(image.height);
+        let mut columns = Vec::with_capacity(image.height);
+        for y in 0..image.height {
+            let lat = (0.5 - (y as f64 + 0.5) / image.height as f64) * f64::consts::PI;
+            let weights: Vec<f64> = (0..image.width)
+                                        .map(|x| {
+                                            let i = (y * image.width + x) * 3;
+                                            let p = &image.pixels[i..i + 3];
+                                            (0.2126 * p[0] as f64 + 0.7152 * p[1] as f64 +
+                                             0.0722 * p[2] as f64) *
+                                            lat.cos()
+                                        })
+                                        .collect();
+            rows.push(weights.iter().fold(0.0, |sum, &w| sum + w));
+            columns.push(cumulative(&weights));
+        }
+
+        if rows.iter().fold(0.0, |sum, &w| sum + w) <= 0.0 {
+            return None;
+        }
+
+        Some(Sampler {
+            rotation: rotation,
+            rows: cumulative(&rows),
+            columns: columns,
+        })
+    }
+
+    // Probability of picking the pixel at column `x` and row `y`.
+    fn probability(&self, x: usize, y: usize) -> f64 {
+        (self.rows[y + 1] - self.rows[y]) * (self.columns[y][x + 1] - self.columns[y][x])
+    }
+
+    // Converts the probability of a pixel into a pdf per unit solid angle at latitude `lat`.
+    fn pdf_at(&self, probability: f64, lat: f64) -> f64 {
+        let pixels = ((self.rows.len() - 1) * (self.columns[0].len() - 1)) as f64;
+        let cos = lat.cos();
+        if cos <= 0.0 {
+            0.0
+        } else {
+            probability * pixels / (2.0 * f64::consts::PI * f64::consts::PI * cos)
+        }
+    }
+
+    // A direction and its pdf per unit solid angle.
+    pub fn sample<R: Rng>(&self, Xi: &mut R) -> (Vector, f64) {
+        let height = self.rows.len() - 1;
+        let y = find(&self.rows, Xi.next_f64());
+        let width = self.columns[y].len() - 1;
+        let x = find(&self.columns[y], Xi.next_f64());
+        let uv = ((x as f64 + Xi.next_f64()) / width as f64,
+                  1.0 - (y as f64 + Xi.next_f64()) / height as f64);
+        let lat = (uv.1 - 0.5) * f64::consts::PI;
+        (dir(uv, self.rotation), self.pdf_at(self.probability(x, y), lat))
+    }
+
+    pub fn pdf(&self, dir: Vector) -> f64 {
+        let height = self.rows.len() - 1;
+        let width = self.columns[0].len() - 1;
+        let uv = uv(dir, self.rotation);
+        let x = ((uv.0 * width as f64) as usize).min(width - 1);
+        let y = (((1.0 - uv.1) * height as f64) as usize).min(height - 1);
+        self.pdf_at(self.probability(x, y), (uv.1 - 0.5) * f64::consts::PI)
+    }
+}
@@ -1,4 +1,4 @@
-use api::{Vector, Ray, Rectangle, Session, Task};
+use api::Task;
 use cairo::{Context, Format, ImageSurface};
 use flate2::read::GzDecoder;
 use gtk;
@@ -12,6 +12,7 @@ use rand::StdRng;
 use render;
 use rustc_serialize::json;
 use rustless::{Application, Api, Nesting};
+use scene::World;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::error::Error;
@@ -22,7 +23,7 @@ use std::result::Result;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::thread;
-use super::{AppError, Args, WorkIterator, SCENE};
+use super::{AppError, Args, WorkIterator};
 
 fn call_mut1<A, B, F: FnMut(A) -> B>(f: &mut F, a: A) -> B {
     f(a)
@@ -41,12 +42,7 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
         Inhibit(false)
     });
 
-    let session = Arc::new(Session::new(1024,
-                                        768,
-                                        args.flag_samples.unwrap_or(1),
-                                        Ray::new(Vector::new(50.0, 52.0, 295.6),
-                                                 Vector::new(0.0, -0.042612, -1.0).norm()),
-                                        SCENE));
+    let world = Arc::new(World::new(try!(super::session(args))));
 
     let (tx_work, rx_work) = mpsc::channel();
     let (_tx_cancel, rx_cancel) = mpsc::channel();
@@ -56,11 +52,11 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
 
     for _ in 0..args.flag_threads.unwrap_or_else(num_cpus::get) {
         let work = work.clone();
-        let session = session.clone();
+        let world = world.clone();
         thread::spawn(move || {
             let mut xi = StdRng::new().unwrap();
             for (tile, tx) in WorkIterator::new(&work) {
-                render::render(&mut xi, &*session, tile, tx);
+                render::render(&mut xi, &*world, tile, tx);
             }
         });
     }
@@ -100,7 +96,7 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
             let mut session_id = String::new();
             client.post(session_url)
                   .header(ContentType::json())
-                  .body(&json::encode(&*session).unwrap())
+                  .body(&json::encode(&world.session).unwrap())
                   .send()
                   .unwrap()
                   .read_to_string(&mut session_id)
@@ -129,40 +125,18 @@ pub fn run(args: &Args) -> Result<i32, Box<Error>> {
         }
     }
 
-    let w = session.width;
-    let h = session.height;
-
-    {
-        let mut tiles = Vec::new();
-        let mut y = 0;
-        while y < h {
-            let mut x = 0;
-            while x < w {
-                tiles.push(Rectangle::new(x, y, 32, 32));
-                x += 32;
-            }
-
-            y += 32;
-        }
-
-        tiles.sort_by_key(|tile| {
-            let tx = tile.left + tile.width / 2;
-            let ty = tile.top + tile.height / 2;
-            let dx = tx as isize - w as isize / 2;
-            let dy = ty as isize - h as isize / 2;
-            (dx * dx + dy * dy, tile.top, tile.left)
-        });
-
-        for tile in tiles {
-            let tx_images = tx_images.clone();
-            try!(tx_work.send((tile,
-                               move |image| {
-                tx_images.lock()
-                         .unwrap()
-                         .send((tile, image))
-                         .unwrap()
-            })));
-        }
+    let w = world.session.width;
+    let h = world.session.height;
+
+    for tile in render::tiles(w, h) {
+        let tx_images = tx_images.clone();
+        try!(tx_work.send((tile,
+                           move |image| {
+            tx_images.lock()
+                     .unwrap()
+                     .send((tile, image))
+                     .unwrap()
+        })));
     }
 
     let area = gtk::DrawingArea::new();
@@ -0,0 +1,120 @@
+use image::{self, ColorType};
+use num_cpus;
+use rand::StdRng;
+use render;
+use scene::World;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use super::{AppError, Args, WorkIterator};
+
+fn write_ppm(path: &Path, width: usize, height: usize, pixels: &[u8]) -> Result<(), Box<Error>> {
+    let mut file = BufWriter::new(try!(File::create(path)));
+    try!(write!(file, "P6\n{} {}\n255\n", width, height));
+    try!(file.write_all(pixels));
+    Ok(())
+}
+
+fn save(path: &Path, width: usize, height: usize, pixels: &[u8]) -> Result<(), Box<Error>> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ppm") => write_ppm(path, width, height, pixels),
+        Some("png") => {
+            try!(image::save_buffer(path,
+                                    pixels,
+                                    width as u32,
+                                    height as u32,
+                                    ColorType::RGB(8)));
+            Ok(())
+        }
+        _ => Err(Box::new(AppError::new("Output file must end in .png or .ppm"))),
+    }
+}
+
+pub fn run(args: &Args) -> Result<i32, Box<Error>> {
+    let threads = args.flag_threads.unwrap_or_else(num_cpus::get);
+    if threads == 0 {
+        return Err(Box::new(AppError::new("--threads must be at least 1")));
+    }
+
+    let world = Arc::new(World::new(try!(super::session(args))));
+
+    let (tx_work, rx_work) = mpsc::channel();
+    let (tx_cancel, rx_cancel) = mpsc::channel();
+    let (tx_images, rx_images) = mpsc::channel();
+    let tx_images = Arc::new(Mutex::new(tx_images));
+    let work = Arc::new(Mutex::new((rx_work, rx_cancel)));
+
+    let mut workers = Vec::new();
+    for _ in 0..threads {
+        let work = work.clone();
+        let world = world.clone();
+        workers.push(thread::spawn(move || {
+            let mut xi = StdRng::new().unwrap();
+            for (tile, tx) in WorkIterator::new(&work) {
+                render::render(&mut xi, &*world, tile, tx);
+            }
+        }));
+    }
+
+    let w = world.session.width;
+    let h = world.session.height;
+    let tiles = render::tiles(w, h);
+    let total_images = tiles.len() * world.session.samples;
+    for tile in tiles {
+        let tx_images = tx_images.clone();
+        try!(tx_work.send((tile,
+                           move |image| {
+            tx_images.lock()
+                     .unwrap()
+                     .send((tile, image))
+                     .unwrap()
+        })));
+    }
+
+    // Once the queued tiles and the threads working on them hold the only senders, the channel
+    // closes early if a thread panics and drops its tile.
+    drop(work);
+    drop(tx_images);
+
+    // Each image sent by render::render holds the running average of all samples so far, so the
+    // last image received for a tile is the finished one.
+    let mut pixels = vec![0; w * h * 3];
+    let mut complete = true;
+    for _ in 0..total_images {
+        let (rect, image): (_, Vec<u8>) = match rx_images.recv() {
+            Ok(message) => message,
+            Err(_) => {
+                complete = false;
+                break;
+            }
+        };
+
+        for y in 0..rect.height {
+            for x in 0..rect.width {
+                let src = (y * rect.width + x) * 4;
+                let dst = ((rect.top + y) * w + rect.left + x) * 3;
+                pixels[dst..dst + 3].copy_from_slice(&image[src..src + 3]);
+            }
+        }
+    }
+
+    drop(tx_cancel);
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(Box::new(AppError::new("A rendering thread panicked")));
+        }
+    }
+
+    if !complete {
+        return Err(Box::new(AppError::new("Rendering stopped before every tile was finished")));
+    }
+
+    let path = Path::new(&args.flag_output);
+    try!(save(path, w, h, &pixels));
+    println!("Wrote {}", path.display());
+    Ok(0)
+}
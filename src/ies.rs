@@ -0,0 +1,285 @@
+use api::Profile;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::u32;
+use super::AppError;
+
+// Reads the candela distribution from an IES LM-63 file, of any version from 1986 to 2002. Only
+// type C photometry, which nearly all luminaires use, is supported. Tilt data only matters for
+// luminaires mounted at an angle and is skipped.
+pub fn load(path: &Path) -> Result<Profile, AppError> {
+    let mut data = Vec::new();
+    try!(File::open(path)
+             .and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+    parse(&data).map_err(|message| AppError::new(format!("{}: {}", path.display(), message)))
+}
+
+// The next `n` numbers, checked to be there before anything is made of them.
+fn take<'a>(numbers: &mut &'a [f64], n: usize) -> Result<&'a [f64], String> {
+    if n > numbers.len() {
+        return Err("unexpected end of file".to_string());
+    }
+
+    let (taken, rest) = numbers.split_at(n);
+    *numbers = rest;
+    Ok(taken)
+}
+
+// A count of angles or tilt pairs, which must be a whole number of at least one. One larger than
+// the rest of the file is caught by `take`.
+fn count(x: f64, what: &str) -> Result<usize, String> {
+    if x >= 1.0 && x <= u32::MAX as f64 && x.fract() == 0.0 {
+        Ok(x as usize)
+    } else {
+        Err(format!("invalid number of {} \"{}\"", what, x))
+    }
+}
+
+// Parses the contents of an IES file, leaving the caller to say which file an error is in.
+pub fn parse(data: &[u8]) -> Result<Profile, String> {
+    let fail = |message: &str| message.to_string();
+
+    // Keywords may be in any encoding, so only the numbers after the TILT line are decoded.
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines();
+    let mut tilt = None;
+    while let Some(line) = lines.next() {
+        if line.trim_left().starts_with("TILT=") {
+            tilt = Some(line.trim_left()[5..].trim().to_string());
+            break;
+        }
+    }
+
+    let tilt = try!(tilt.ok_or_else(|| fail("missing TILT line")));
+
+    let mut numbers = Vec::new();
+    for field in lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+                      .filter(|field| !field.is_empty()) {
+        numbers.push(try!(field.parse::<f64>()
+                               .map_err(|_| fail(&format!("invalid number \"{}\"", field)))));
+    }
+
+    let mut rest = &numbers[..];
+    if tilt == "INCLUDE" {
+        let pairs = try!(count(try!(take(&mut rest, 2))[1], "tilt angles"));
+        let n = try!(pairs.checked_mul(2).ok_or_else(|| fail("too many tilt angles")));
+        try!(take(&mut rest, n));
+    }
+
+    // Lamps, lumens, the multiplier, the numbers of angles, the type of photometry, units, the
+    // size of the luminous opening, the two ballast factors and watts.
+    let header = try!(take(&mut rest, 13));
+    let nv = try!(count(header[3], "vertical angles"));
+    let nh = try!(count(header[4], "horizontal angles"));
+    if header[5] != 1.0 {
+        return Err(fail("only type C photometry is supported"));
+    }
+
+    let vertical = try!(take(&mut rest, nv)).to_vec();
+    let horizontal = try!(take(&mut rest, nh)).to_vec();
+    let scale = header[2] * header[10] * header[11];
+    let n = try!(nv.checked_mul(nh).ok_or_else(|| fail("too many angles")));
+    let candela: Vec<f64> = try!(take(&mut rest, n)).iter().map(|c| c * scale).collect();
+    if vertical.windows(2).any(|w| w[1] <= w[0]) || horizontal.windows(2).any(|w| w[1] <= w[0]) {
+        return Err(fail("angles must increase"));
+    }
+
+    Ok(Profile {
+        vertical: vertical,
+        horizontal: horizontal,
+        candela: candela,
+    })
+}
+
+// Index of the angle at or before `x` and how far `x` lies towards the next one, clamped to the
+// ends of `angles`.
+fn locate(angles: &[f64], x: f64) -> (usize, f64) {
+    let n = angles.len();
+    if n == 1 || x <= angles[0] {
+        return (0, 0.0);
+    }
+
+    if x >= angles[n - 1] {
+        return (n - 2, 1.0);
+    }
+
+    let mut i = 0;
+    while angles[i + 1] < x {
+        i += 1;
+    }
+
+    (i, (x - angles[i]) / (angles[i + 1] - angles[i]))
+}
+
+impl Profile {
+    // Candelas at `vertical` degrees from the axis and `horizontal` degrees about it, interpolated
+    // between the measured angles. The range of horizontal angles in the file says how the
+    // distribution is symmetric: a single angle for all round, 0 to 90 for each quadrant alike, 0
+    // to 180 or 90 to 270 for two mirrored halves, or the full circle.
+    pub fn candela(&self, vertical: f64, horizontal: f64) -> f64 {
+        let nv = self.vertical.len();
+        if vertical < self.vertical[0] || vertical > self.vertical[nv - 1] {
+            return 0.0;
+        }
+
+        let (v, tv) = locate(&self.vertical, vertical);
+        let value = |h: usize| {
+            let c = &self.candela[h * nv..(h + 1) * nv];
+            if tv > 0.0 {
+                c[v] * (1.0 - tv) + c[v + 1] * tv
+            } else {
+                c[v]
+            }
+        };
+
+        let nh = self.horizontal.len();
+        if nh == 1 {
+            return value(0);
+        }
+
+        let first = self.horizontal[0];
+        let last = self.horizontal[nh - 1];
+        let h = horizontal - 360.0 * (horizontal / 360.0).floor();
+        let h = if last <= 90.0 {
+            let h = h % 180.0;
+            if h > 90.0 { 180.0 - h } else { h }
+        } else if first >= 90.0 && last <= 270.0 {
+            if h < 90.0 {
+                180.0 - h
+            } else if h > 270.0 {
+                540.0 - h
+            } else {
+                h
+            }
+        } else if last <= 180.0 {
+            if h > 180.0 { 360.0 - h } else { h }
+        } else {
+            h
+        };
+
+        // A full circle need not repeat its first angle at 360 degrees.
+        if h > last && last > 180.0 {
+            let t = (h - last) / (first + 360.0 - last);
+            return value(nh - 1) * (1.0 - t) + value(0) * t;
+        }
+
+        let (i, th) = locate(&self.horizontal, h);
+        if th > 0.0 {
+            value(i) * (1.0 - th) + value(i + 1) * th
+        } else {
+            value(i)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::Profile;
+    use super::parse;
+
+    // A profile whose candela at each horizontal angle is that angle, so that a lookup gives back
+    // the angle it was folded onto.
+    fn profile(horizontal: &[f64]) -> Profile {
+        Profile {
+            vertical: vec![0.0, 180.0],
+            horizontal: horizontal.to_vec(),
+            candela: horizontal.iter().flat_map(|&h| vec![h, h]).collect(),
+        }
+    }
+
+    fn assert_folds(profile: &Profile, pairs: &[(f64, f64)]) {
+        for &(h, folded) in pairs {
+            let c = profile.candela(45.0, h);
+            assert!((c - folded).abs() < 1e-9, "{} gave {}, not {}", h, c, folded);
+        }
+    }
+
+    #[test]
+    fn quadrant() {
+        let p = profile(&[0.0, 45.0, 90.0]);
+        assert_folds(&p,
+                     &[(30.0, 30.0), (100.0, 80.0), (200.0, 20.0), (300.0, 60.0), (-30.0, 30.0)]);
+    }
+
+    #[test]
+    fn half_0_180() {
+        let p = profile(&[0.0, 90.0, 180.0]);
+        assert_folds(&p, &[(30.0, 30.0), (150.0, 150.0), (200.0, 160.0), (330.0, 30.0)]);
+    }
+
+    #[test]
+    fn half_90_270() {
+        let p = profile(&[90.0, 180.0, 270.0]);
+        assert_folds(&p, &[(120.0, 120.0), (250.0, 250.0), (45.0, 135.0), (300.0, 240.0)]);
+    }
+
+    #[test]
+    fn full_circle() {
+        let p = profile(&[0.0, 90.0, 180.0, 270.0]);
+        assert_folds(&p, &[(45.0, 45.0), (200.0, 200.0), (270.0, 270.0)]);
+
+        // Past the last angle, the candela runs back to that of the first.
+        let c = p.candela(45.0, 315.0);
+        assert!((c - 135.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn single_angle() {
+        let p = profile(&[0.0]);
+        assert_folds(&p, &[(0.0, 0.0), (123.0, 0.0)]);
+    }
+
+    const FILE: &'static str = concat!("IESNA:LM-63-2002\n",
+                                       "[TEST] test\n",
+                                       "TILT=NONE\n",
+                                       "1 -1 2 3 2 1 2 0 0 0\n",
+                                       "1.0 1.0 100\n",
+                                       "0 45 90\n",
+                                       "0 90\n",
+                                       "10 5 0\n",
+                                       "20 10 0\n");
+
+    #[test]
+    fn file() {
+        let p = parse(FILE.as_bytes()).unwrap();
+        assert_eq!(p.vertical, vec![0.0, 45.0, 90.0]);
+        assert_eq!(p.horizontal, vec![0.0, 90.0]);
+        assert_eq!(p.candela, vec![20.0, 10.0, 0.0, 40.0, 20.0, 0.0]);
+    }
+
+    #[test]
+    fn truncated() {
+        // Cut off in the candela values, before the angles, and in the header.
+        let text = FILE.trim_right();
+        let ends = [text.len() - 2, text.find("0 45").unwrap(), text.find("1.0 1.0").unwrap()];
+        for &end in ends.iter() {
+            assert!(parse(text[..end].as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn counts() {
+        // Zero, fractional, negative and not numbers at all, then more than the file holds.
+        let angles = [("0", "2"), ("3", "0"), ("2.5", "2"), ("-3", "2"), ("NaN", "2"), ("3", "inf"),
+                      ("1e300", "2"), ("3", "4294967295"), ("4", "2")];
+        for &(nv, nh) in angles.iter() {
+            let text = FILE.replace("1 -1 2 3 2 1", &format!("1 -1 2 {} {} 1", nv, nh));
+            assert!(parse(text.as_bytes()).is_err(), "{} by {} angles", nv, nh);
+        }
+
+        for &pairs in ["0", "1.5", "1e19", "100"].iter() {
+            let text = FILE.replace("TILT=NONE\n", &format!("TILT=INCLUDE\n1 {}\n", pairs));
+            assert!(parse(text.as_bytes()).is_err(), "{} tilt pairs", pairs);
+        }
+
+        let text = FILE.replace("TILT=NONE\n", "TILT=INCLUDE\n1 2\n0 90\n1 0.5\n");
+        assert_eq!(parse(text.as_bytes()).unwrap().candela.len(), 6);
+    }
+
+    #[test]
+    fn missing_tilt() {
+        assert!(parse(b"IESNA:LM-63-2002\n1 -1 1\n").is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use api::{Light, LightKind, Vector};
+use std::f64;
+
+fn smoothstep(a: f64, b: f64, x: f64) -> f64 {
+    if a >= b {
+        return if x >= b { 1.0 } else { 0.0 };
+    }
+
+    let t = ((x - a) / (b - a)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Directions from which the horizontal angles of an IES profile are measured, for a light whose
+// unit axis is `axis`: 0 degrees faces the part of the x axis across the axis, or the z axis for a
+// light that points along x, turned by `rotation` radians, and 90 degrees is a right angle on.
+fn frame(axis: Vector, rotation: f64) -> (Vector, Vector) {
+    let reference = if axis.x.abs() > 0.9 {
+        Vector::new(0.0, 0.0, 1.0)
+    } else {
+        Vector::new(1.0, 0.0, 0.0)
+    };
+
+    let u0 = (reference - axis * reference.dot(axis)).norm();
+    let v0 = axis.cross(u0);
+    let u = u0 * rotation.cos() + v0 * rotation.sin();
+    (u, axis.cross(u))
+}
+
+impl Light {
+    // The unit direction from `pos` towards the light, the distance to it, and the irradiance it
+    // gives a surface at `pos` facing it.
+    pub fn illuminate(&self, pos: Vector) -> (Vector, f64, Vector) {
+        if let LightKind::Directional = self.kind {
+            return (self.direction * -1.0, f64::INFINITY, self.intensity);
+        }
+
+        let to_light = self.position - pos;
+        let dist2 = to_light.dot(to_light);
+        let dist = dist2.sqrt();
+        let l = to_light / dist;
+        let w = l * -1.0;
+        let profile = match self.kind {
+            LightKind::Point => 1.0,
+            LightKind::Spot(cos_inner, cos_outer) => {
+                smoothstep(cos_outer, cos_inner, w.dot(self.direction))
+            }
+            LightKind::Ies(ref profile, rotation) => {
+                let (u, v) = frame(self.direction, rotation);
+                let vertical = w.dot(self.direction).max(-1.0).min(1.0).acos();
+                let horizontal = w.dot(v).atan2(w.dot(u));
+                profile.candela(vertical.to_degrees(), horizontal.to_degrees())
+            }
+            LightKind::Directional => unreachable!(),
+        };
+
+        (l, dist, self.intensity * (profile / dist2))
+    }
+}
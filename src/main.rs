@@ -7,6 +7,7 @@ extern crate flate2;
 extern crate glib;
 extern crate gtk;
 extern crate hyper;
+extern crate image;
 extern crate iron;
 extern crate num_cpus;
 extern crate rand;
@@ -32,66 +33,34 @@ macro_rules! clone {
 
 mod agent;
 mod api;
+mod bench;
+mod bsdf;
+mod bvh;
+mod environment;
 mod gui;
+mod headless;
+mod ies;
+mod light;
+mod medium;
+mod obj;
 mod radiance;
 mod render;
 mod scene;
+mod scene_file;
+mod sky;
+mod texture;
+mod volume;
 
-use api::{Sphere, Vector};
-use api::Refl::*;
+use api::Session;
 use std::error::Error;
 use std::fmt;
 use std::io::{Write, stderr};
+use std::path::Path;
 use std::process;
 use std::sync::mpsc::Receiver;
 use std::sync::Mutex;
 
-// Scene: radius, position, emission, color, material
-static SCENE: &'static [Sphere] = &[Sphere::new(1e5,
-                                                Vector::new(1e5 + 1.0, 40.8, 81.6),
-                                                Vector::zero(),
-                                                Vector::new(0.75, 0.25, 0.25),
-                                                Diff), // Left
-                                    Sphere::new(1e5,
-                                                Vector::new(-1e5 + 99.0, 40.8, 81.6),
-                                                Vector::zero(),
-                                                Vector::new(0.25, 0.25, 0.75),
-                                                Diff), // Rght
-                                    Sphere::new(1e5,
-                                                Vector::new(50.0, 40.8, 1e5),
-                                                Vector::zero(),
-                                                Vector::new(0.75, 0.75, 0.75),
-                                                Diff), // Back
-                                    Sphere::new(1e5,
-                                                Vector::new(50.0, 40.8, -1e5 + 170.0),
-                                                Vector::zero(),
-                                                Vector::zero(),
-                                                Diff), // Frnt
-                                    Sphere::new(1e5,
-                                                Vector::new(50.0, 1e5, 81.6),
-                                                Vector::zero(),
-                                                Vector::new(0.75, 0.75, 0.75),
-                                                Diff), // Botm
-                                    Sphere::new(1e5,
-                                                Vector::new(50.0, -1e5 + 81.6, 81.6),
-                                                Vector::zero(),
-                                                Vector::new(0.75, 0.75, 0.75),
-                                                Diff), // Top
-                                    Sphere::new(16.5,
-                                                Vector::new(27.0, 16.5, 47.0),
-                                                Vector::zero(),
-                                                Vector::new(0.999, 0.999, 0.999),
-                                                Spec), // Mirr
-                                    Sphere::new(16.5,
-                                                Vector::new(73.0, 16.5, 78.0),
-                                                Vector::zero(),
-                                                Vector::new(0.999, 0.999, 0.999),
-                                                Refr), // Glas
-                                    Sphere::new(600.0,
-                                                Vector::new(50.0, 681.6 - 0.27, 81.6),
-                                                Vector::new(12.0, 12.0, 12.0),
-                                                Vector::zero(),
-                                                Diff) /* Lite */];
+static DEFAULT_SCENE: &'static str = include_str!("../scenes/cornell.json");
 
 struct WorkIterator<'a, T: 'a>(&'a Mutex<(Receiver<T>, Receiver<()>)>);
 
@@ -114,31 +83,53 @@ impl<'a, T: 'a + Send> Iterator for WorkIterator<'a, T> {
 }
 
 #[derive(Debug)]
-struct AppError<'a>(&'a str);
+struct AppError(String);
 
-impl<'a> AppError<'a> {
-    pub fn new(desc: &'a str) -> Self {
-        AppError(desc)
+impl AppError {
+    pub fn new<S: Into<String>>(desc: S) -> Self {
+        AppError(desc.into())
     }
 }
 
-impl<'a> fmt::Display for AppError<'a> {
+impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.0)
+        f.write_str(&self.0)
     }
 }
 
-impl<'a> Error for AppError<'a> {
+impl Error for AppError {
     fn description(&self) -> &str {
-        self.0
+        &self.0
     }
 }
 
+pub fn session(args: &Args) -> Result<Session, AppError> {
+    let mut session = try!(match args.flag_scene {
+        Some(ref path) => scene_file::load(Path::new(path)),
+        None => scene_file::parse(DEFAULT_SCENE, Path::new("")),
+    });
+
+    if let Some(samples) = args.flag_samples {
+        if samples == 0 {
+            return Err(AppError::new("--samples must be at least 1"));
+        }
+
+        session.samples = samples;
+    }
+
+    Ok(session)
+}
+
 #[derive(RustcDecodable)]
 pub struct Args {
+    pub cmd_bench: bool,
+    pub cmd_render: bool,
     pub cmd_serve: bool,
     pub flag_agent: Vec<String>,
+    pub flag_output: String,
     pub flag_samples: Option<usize>,
+    pub flag_scene: Option<String>,
+    pub flag_spheres: Option<usize>,
     pub flag_threads: Option<usize>,
 }
 
@@ -146,17 +137,22 @@ const USAGE: &'static str = "
 smallpt, a distributed path tracer.
 
 Usage:
-  smallpt [--samples=<n>] [--threads=<n>][--agent=<url>...]
+  smallpt [--scene=<file>] [--samples=<n>] [--threads=<n>] [--agent=<url>...]
+  smallpt render --output=<file> [--scene=<file>] [--samples=<n>] [--threads=<n>]
   smallpt serve [--threads=<n>]
+  smallpt bench [--spheres=<n>]
   smallpt (-h | --help)
   smallpt --version
 
 Options:
-  -h --help      Show this screen.
-  --version      Show version.
-  --samples=<n>  Number of samples per pixel. Defaults to 1.
-  --threads=<n>  Number of threads for parallel rendering. Defaults to the number of CPU cores.
-  --agent=<url>  Connect to a remote agent.
+  -h --help        Show this screen.
+  --version        Show version.
+  --scene=<file>   Load the scene from a JSON file. Defaults to the Cornell box.
+  --samples=<n>    Number of samples per pixel. Overrides the scene file.
+  --threads=<n>    Number of threads for parallel rendering. Defaults to the number of CPU cores.
+  --agent=<url>    Connect to a remote agent.
+  --output=<file>  Write the finished image to a .png or .ppm file.
+  --spheres=<n>    Number of random spheres to trace against. Defaults to 10000.
 ";
 
 use docopt::Docopt;
@@ -168,6 +164,10 @@ fn main() {
 
     let run = if args.cmd_serve {
         agent::run
+    } else if args.cmd_render {
+        headless::run
+    } else if args.cmd_bench {
+        bench::run
     } else {
         gui::run
     };
@@ -0,0 +1,191 @@
+#![allow(non_snake_case)]
+use api::{Grid, Medium, Ray, Vector};
+use bsdf::basis;
+use rand::Rng;
+use std::f64;
+
+// Beer–Lambert law: the fraction of light left after travelling `dist` through a medium with
+// extinction coefficient `sigma`. A channel that nothing happens to passes an infinite distance.
+pub fn transmittance(sigma: Vector, dist: f64) -> Vector {
+    let channel = |sigma: f64| if sigma > 0.0 { (-sigma * dist).exp() } else { 1.0 };
+    Vector::new(channel(sigma.x), channel(sigma.y), channel(sigma.z))
+}
+
+// The part of `ray` up to distance `max` that lies in the box from `lower` to `upper`, if any, by
+// the same slab test as the BVH's.
+pub fn clip(lower: Vector, upper: Vector, ray: Ray, max: f64) -> Option<(f64, f64)> {
+    let mut t0 = 0.0f64;
+    let mut t1 = max;
+    for &(lower, upper, o, d) in &[(lower.x, upper.x, ray.o.x, ray.d.x),
+                                   (lower.y, upper.y, ray.o.y, ray.d.y),
+                                   (lower.z, upper.z, ray.o.z, ray.d.z)] {
+        let near = (lower - o) / d;
+        let far = (upper - o) / d;
+        t0 = t0.max(near.min(far));
+        t1 = t1.min(near.max(far));
+    }
+
+    if t0 < t1 { Some((t0, t1)) } else { None }
+}
+
+fn mean(v: Vector) -> f64 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+// Henyey–Greenstein phase function, for directions at angle acos(`cos`) to each other.
+fn henyey_greenstein(g: f64, cos: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * f64::consts::PI * denom * denom.sqrt())
+}
+
+impl Medium {
+    pub fn extinction(&self) -> Vector {
+        self.absorption + self.scattering
+    }
+
+    pub fn scatters(&self) -> bool {
+        self.scattering.x.max(self.scattering.y).max(self.scattering.z) > 0.0
+    }
+
+    pub fn transmittance(&self, dist: f64) -> Vector {
+        transmittance(self.extinction(), dist)
+    }
+
+    // Picks how far a ray travels before it scatters, sampling the distance for one channel.
+    // `ratio` holds each channel's pdf for the path so far relative to their mean, and the channel
+    // is picked in proportion to it, which weights the whole path against the same path sampled
+    // for the other channels rather than each distance on its own; otherwise the channels'
+    // weights drift apart over a long walk. Returns the distance, or None if the ray gets `max`
+    // along first, the weight to multiply the path by, and the new ratios.
+    pub fn sample_distance<R: Rng>(&self,
+                                   Xi: &mut R,
+                                   max: f64,
+                                   ratio: Vector)
+                                   -> (Option<f64>, Vector, Vector) {
+        let sigma = self.extinction();
+        let pick = Xi.next_f64() * (ratio.x + ratio.y + ratio.z);
+        let channel = if pick < ratio.x {
+            sigma.x
+        } else if pick < ratio.x + ratio.y {
+            sigma.y
+        } else {
+            sigma.z
+        };
+
+        let t = if channel > 0.0 {
+            -(1.0 - Xi.next_f64()).ln() / channel
+        } else {
+            f64::INFINITY
+        };
+
+        let (t, f, pdf) = if t < max {
+            let tr = transmittance(sigma, t);
+            (Some(t), self.scattering * tr, sigma * tr)
+        } else {
+            let tr = transmittance(sigma, max);
+            (None, tr, tr)
+        };
+
+        let p = mean(ratio * pdf);
+        if p > 0.0 {
+            (t, f / p, ratio * pdf / p)
+        } else {
+            (t, Vector::zero(), ratio)
+        }
+    }
+
+    // Delta tracking through the medium scaled by `grid`'s density, for a ray reaching a surface at
+    // `max`. Tentative collisions come at the rate of the densest part of the grid, and each is a
+    // real one, where the ray scatters, or a null one, where it carries on, in proportion to the
+    // local density. Picking between them for all channels together, with `ratio` as in
+    // sample_distance, returns the same as that does.
+    pub fn track<R: Rng>(&self,
+                         grid: &Grid,
+                         Xi: &mut R,
+                         ray: Ray,
+                         max: f64,
+                         ratio: Vector)
+                         -> (Option<f64>, Vector, Vector) {
+        let sigma = self.extinction();
+        let majorant = sigma.x.max(sigma.y).max(sigma.z) * grid.max_density;
+        let (mut t, end) = match grid.clip(ray, max) {
+            Some(span) if majorant > 0.0 => span,
+            _ => return (None, Vector::one(), ratio),
+        };
+
+        let mut weight = Vector::one();
+        let mut ratio = ratio;
+        loop {
+            t -= (1.0 - Xi.next_f64()).ln() / majorant;
+            if t >= end {
+                return (None, weight, ratio);
+            }
+
+            let density = grid.density(ray.o + ray.d * t);
+            let real = sigma * density;
+            let p = mean(ratio * real);
+            if Xi.next_f64() * majorant < p {
+                return (Some(t), weight * self.scattering * density / p, ratio * real / p);
+            }
+
+            let null = Vector::new(majorant, majorant, majorant) - real;
+            let q = mean(ratio * null);
+            weight = weight * null / q;
+            ratio = ratio * null / q;
+        }
+    }
+
+    // Ratio tracking: an unbiased estimate of the transmittance along `ray` up to `max` through the
+    // medium scaled by `grid`'s density, as the product of the chances of a null collision at each
+    // tentative collision.
+    pub fn estimate_transmittance<R: Rng>(&self,
+                                          grid: &Grid,
+                                          Xi: &mut R,
+                                          ray: Ray,
+                                          max: f64)
+                                          -> Vector {
+        let sigma = self.extinction();
+        let majorant = sigma.x.max(sigma.y).max(sigma.z) * grid.max_density;
+        let (mut t, end) = match grid.clip(ray, max) {
+            Some(span) if majorant > 0.0 => span,
+            _ => return Vector::one(),
+        };
+
+        let mut tr = Vector::one();
+        loop {
+            t -= (1.0 - Xi.next_f64()).ln() / majorant;
+            if t >= end {
+                return tr;
+            }
+
+            let density = grid.density(ray.o + ray.d * t);
+            tr = tr * (Vector::one() - sigma * (density / majorant));
+        }
+    }
+
+    // Pdf per unit solid angle of scattering along `wi` a ray travelling along `dir`, which is also
+    // the phase function itself.
+    pub fn phase(&self, dir: Vector, wi: Vector) -> f64 {
+        henyey_greenstein(self.g, dir.dot(wi))
+    }
+
+    // Samples the phase function exactly, so the direction's weight is one. Returns the direction
+    // and its pdf.
+    pub fn sample_phase<R: Rng>(&self, Xi: &mut R, dir: Vector) -> (Vector, f64) {
+        let g = self.g;
+        let eps1 = Xi.next_f64();
+        let eps2 = Xi.next_f64();
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * eps1
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * eps1);
+            ((1.0 + g * g - s * s) / (2.0 * g)).max(-1.0).min(1.0)
+        };
+
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * f64::consts::PI * eps2;
+        let (u, v) = basis(dir);
+        let wi = (u * phi.cos() * sin + v * phi.sin() * sin + dir * cos).norm();
+        (wi, henyey_greenstein(g, cos))
+    }
+}
@@ -0,0 +1,519 @@
+use api::{Mesh, Param, Principled, Refl, Shape, Textures, Vector};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use super::AppError;
+use texture::Library;
+
+struct Material {
+    kd: Vector,
+    ks: Vector,
+    ke: Vector,
+    tf: Option<Vector>,
+    ni: f64,
+    dissolve: f64,
+    illum: u32,
+    pr: Option<f64>,
+    pm: f64,
+    textures: Textures,
+}
+
+impl Material {
+    fn new() -> Self {
+        Material {
+            kd: Vector::new(0.8, 0.8, 0.8),
+            ks: Vector::zero(),
+            ke: Vector::zero(),
+            tf: None,
+            ni: 1.5,
+            dissolve: 1.0,
+            illum: 1,
+            pr: None,
+            pm: 0.0,
+            textures: Textures::none(),
+        }
+    }
+
+    // Map the MTL illumination model onto the nearest of our materials. Returns emission, colour
+    // and material in the same form as the other shapes. Materials that give a roughness, as in
+    // the PBR extension to MTL, become principled.
+    fn surface(&self) -> (Vector, Vector, Refl) {
+        let kd = self.kd.x.max(self.kd.y).max(self.kd.z);
+        let ks = self.ks.x.max(self.ks.y).max(self.ks.z);
+        let pbr = self.pr.is_some() || self.textures.roughness.is_some();
+        let ke = self.emission();
+        match self.illum {
+            4 | 6 | 7 | 9 => (ke, self.transmission(), Refl::Refr(self.ni, Vector::zero())),
+            _ if self.dissolve < 1.0 => {
+                (ke, self.transmission(), Refl::Refr(self.ni, Vector::zero()))
+            }
+            _ if pbr => {
+                (ke,
+                 self.kd,
+                 Refl::Principled(Principled {
+                     base_color: Param::Value(Vector::one()),
+                     metallic: Param::Value(self.pm),
+                     roughness: Param::Value(self.pr.unwrap_or(0.5)),
+                     specular: Param::Value(0.5),
+                     clearcoat: Param::Value(0.0),
+                     sheen: Param::Value(0.0),
+                     transmission: Param::Value(0.0),
+                 }))
+            }
+            3 | 5 => (ke, self.ks, Refl::Spec),
+            // The two lobes share one colour, scaled down where it would reflect more light than
+            // arrives.
+            2 if ks > 0.0 && kd > 0.0 => {
+                let color = self.kd + self.ks;
+                (ke,
+                 color / color.x.max(color.y).max(color.z).max(1.0),
+                 Refl::Mix(Param::Value(ks / (kd + ks)),
+                           Box::new(Refl::Diff),
+                           Box::new(Refl::Spec)))
+            }
+            2 if ks > 0.0 => (ke, self.ks, Refl::Spec),
+            _ => (ke, self.kd, Refl::Diff),
+        }
+    }
+
+    // An emission map is scaled by Ke, which usually defaults to black. This is settled once the
+    // whole material has been read, since Ke may come before or after the map.
+    fn emission(&self) -> Vector {
+        if self.textures.emission.is_some() && self.ke.x.max(self.ke.y).max(self.ke.z) <= 0.0 {
+            Vector::one()
+        } else {
+            self.ke
+        }
+    }
+
+    fn transmission(&self) -> Vector {
+        self.tf.unwrap_or(Vector::new(0.999, 0.999, 0.999))
+    }
+}
+
+fn error<T>(path: &Path, line: usize, message: &str) -> Result<T, AppError> {
+    Err(AppError::new(format!("{}:{}: {}", path.display(), line, message)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, AppError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| AppError::new(format!("{}: {}", path.display(), err)))
+}
+
+fn numbers(path: &Path, line: usize, args: &[&str], count: usize) -> Result<Vec<f64>, AppError> {
+    if args.len() < count {
+        return error(path, line, &format!("expected {} numbers", count));
+    }
+
+    let mut numbers = Vec::with_capacity(count);
+    for arg in &args[..count] {
+        match arg.parse() {
+            Ok(n) => numbers.push(n),
+            Err(_) => return error(path, line, &format!("invalid number \"{}\"", arg)),
+        }
+    }
+
+    Ok(numbers)
+}
+
+fn vector(path: &Path, line: usize, args: &[&str]) -> Result<Vector, AppError> {
+    let n = try!(numbers(path, line, args, 3));
+    Ok(Vector::new(n[0], n[1], n[2]))
+}
+
+// Texture maps may be preceded by options, so the file name is taken to be the last argument.
+fn map(path: &Path,
+       line: usize,
+       args: &[&str],
+       srgb: bool,
+       library: &mut Library)
+       -> Result<Option<usize>, AppError> {
+    let name = match args.last() {
+        Some(name) => name,
+        None => return error(path, line, "expected a file name"),
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    library.load(&dir.join(name), srgb)
+           .map(Some)
+           .map_err(|err| AppError::new(format!("{}:{}: {}", path.display(), line, err)))
+}
+
+fn load_mtl(path: &Path,
+            materials: &mut HashMap<String, Material>,
+            library: &mut Library)
+            -> Result<(), AppError> {
+    parse_mtl(try!(open(path)), path, materials, library)
+}
+
+// Reads a material library from `reader`. `path` is the file it came from, which errors name and
+// texture maps are found relative to.
+fn parse_mtl<R: BufRead>(reader: R,
+                         path: &Path,
+                         materials: &mut HashMap<String, Material>,
+                         library: &mut Library)
+                         -> Result<(), AppError> {
+    let mut current = None;
+    for (i, text) in reader.lines().enumerate() {
+        let line = i + 1;
+        let text = try!(text.map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+        let mut args = text.split_whitespace();
+        let keyword = match args.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        let args: Vec<&str> = args.collect();
+        if keyword == "newmtl" {
+            let name = args.join(" ");
+            materials.insert(name.clone(), Material::new());
+            current = Some(name);
+            continue;
+        }
+
+        let material = match current {
+            Some(ref name) => materials.get_mut(name).unwrap(),
+            None => return error(path, line, "expected newmtl"),
+        };
+
+        match keyword {
+            "Kd" => material.kd = try!(vector(path, line, &args)),
+            "Ks" => material.ks = try!(vector(path, line, &args)),
+            "Ke" => material.ke = try!(vector(path, line, &args)),
+            "Tf" => material.tf = Some(try!(vector(path, line, &args))),
+            "Ni" => material.ni = try!(numbers(path, line, &args, 1))[0],
+            "d" => material.dissolve = try!(numbers(path, line, &args, 1))[0],
+            "Tr" => material.dissolve = 1.0 - try!(numbers(path, line, &args, 1))[0],
+            "illum" => material.illum = try!(numbers(path, line, &args, 1))[0] as u32,
+            "Pr" => material.pr = Some(try!(numbers(path, line, &args, 1))[0]),
+            "Pm" => material.pm = try!(numbers(path, line, &args, 1))[0],
+            "map_Kd" => material.textures.color = try!(map(path, line, &args, true, library)),
+            "map_Ke" => material.textures.emission = try!(map(path, line, &args, true, library)),
+            "map_Pr" => material.textures.roughness = try!(map(path, line, &args, false, library)),
+            "norm" => material.textures.normal = try!(map(path, line, &args, false, library)),
+            "bump" | "map_Bump" | "map_bump" => {
+                material.textures.bump = try!(map(path, line, &args, false, library));
+                if let Some(i) = args.iter().position(|&arg| arg == "-bm") {
+                    material.textures.bump_scale = try!(numbers(path, line, &args[i + 1..], 1))[0];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Faces that share a material become one mesh, with its own copy of the vertices it uses.
+struct Group {
+    vertices: Vec<Vector>,
+    normals: Vec<Vector>,
+    uvs: Vec<(f64, f64)>,
+    faces: Vec<(usize, usize, usize)>,
+    smooth: bool,
+    textured: bool,
+    indices: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+}
+
+impl Group {
+    fn new() -> Self {
+        Group {
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            faces: Vec::new(),
+            smooth: true,
+            textured: true,
+            indices: HashMap::new(),
+        }
+    }
+
+    fn vertex(&mut self,
+              positions: &[Vector],
+              uvs: &[(f64, f64)],
+              normals: &[Vector],
+              key: (usize, Option<usize>, Option<usize>))
+              -> usize {
+        if let Some(&index) = self.indices.get(&key) {
+            return index;
+        }
+
+        let index = self.vertices.len();
+        self.vertices.push(positions[key.0]);
+        match key.1 {
+            Some(t) => self.uvs.push(uvs[t]),
+            None => self.textured = false,
+        }
+
+        match key.2 {
+            Some(n) => self.normals.push(normals[n]),
+            None => self.smooth = false,
+        }
+
+        self.indices.insert(key, index);
+        index
+    }
+}
+
+// OBJ indices are 1-based, or relative to the end of the list when negative.
+fn resolve(path: &Path, line: usize, arg: &str, len: usize) -> Result<usize, AppError> {
+    let index: isize = match arg.parse() {
+        Ok(index) => index,
+        Err(_) => return error(path, line, &format!("invalid index \"{}\"", arg)),
+    };
+
+    let resolved = if index < 0 {
+        len as isize + index
+    } else {
+        index - 1
+    };
+
+    if resolved < 0 || resolved >= len as isize {
+        return error(path, line, &format!("index {} out of range", index));
+    }
+
+    Ok(resolved as usize)
+}
+
+pub fn load(path: &Path, library: &mut Library) -> Result<Vec<Shape>, AppError> {
+    parse(try!(open(path)), path, library)
+}
+
+// Reads a model from `reader`, with `path` playing the same part as for `parse_mtl`.
+fn parse<R: BufRead>(reader: R,
+                     path: &Path,
+                     library: &mut Library)
+                     -> Result<Vec<Shape>, AppError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut materials = HashMap::new();
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut groups: Vec<(String, Group)> = Vec::new();
+    let mut current = 0;
+    groups.push((String::new(), Group::new()));
+
+    for (i, text) in reader.lines().enumerate() {
+        let line = i + 1;
+        let text = try!(text.map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+        let mut args = text.split_whitespace();
+        let keyword = match args.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        let args: Vec<&str> = args.collect();
+        match keyword {
+            "v" => positions.push(try!(vector(path, line, &args))),
+            "vt" => {
+                let n = try!(numbers(path, line, &args, 2));
+                uvs.push((n[0], n[1]));
+            }
+            "vn" => normals.push(try!(vector(path, line, &args)).norm()),
+            "f" => {
+                if args.len() < 3 {
+                    return error(path, line, "expected at least three vertices");
+                }
+
+                let mut face = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let mut parts = arg.split('/');
+                    let v = try!(resolve(path, line, parts.next().unwrap(), positions.len()));
+                    let t = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(try!(resolve(path, line, t, uvs.len()))),
+                        _ => None,
+                    };
+
+                    let n = match parts.next() {
+                        Some(n) if !n.is_empty() => {
+                            Some(try!(resolve(path, line, n, normals.len())))
+                        }
+                        _ => None,
+                    };
+
+                    face.push(groups[current].1.vertex(&positions, &uvs, &normals, (v, t, n)));
+                }
+
+                // Polygons are split into a fan of triangles around their first vertex.
+                for k in 1..face.len() - 1 {
+                    groups[current].1.faces.push((face[0], face[k], face[k + 1]));
+                }
+            }
+            "mtllib" => {
+                for name in &args {
+                    try!(load_mtl(&dir.join(name), &mut materials, library));
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                current = match groups.iter().position(|&(ref n, _)| *n == name) {
+                    Some(index) => index,
+                    None => {
+                        groups.push((name, Group::new()));
+                        groups.len() - 1
+                    }
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let default = Material::new();
+    let mut shapes = Vec::new();
+    for (name, group) in groups {
+        if group.faces.is_empty() {
+            continue;
+        }
+
+        let material = materials.get(&name).unwrap_or(&default);
+        let (e, c, refl) = material.surface();
+        let normals = if group.smooth {
+            group.normals
+        } else {
+            Vec::new()
+        };
+
+        let uvs = if group.textured {
+            group.uvs
+        } else {
+            Vec::new()
+        };
+
+        shapes.push(Shape::Mesh(Mesh {
+            textures: material.textures,
+            ..Mesh::new(group.vertices, normals, uvs, group.faces, e, c, refl)
+        }));
+    }
+
+    Ok(shapes)
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{Refl, Shape, Vector};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use super::{Material, parse, parse_mtl};
+    use texture::Library;
+
+    fn meshes(text: &str) -> Vec<Shape> {
+        parse(text.as_bytes(), Path::new("test.obj"), &mut Library::new()).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text.as_bytes(), Path::new("test.obj"), &mut Library::new()) {
+            Ok(_) => String::new(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    const VERTICES: &'static str = concat!("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\n",
+                                           "vt 0 0\nvt 1 0\nvt 0 1\n",
+                                           "vn 0 0 2\nvn 0 0 -1\n");
+
+    #[test]
+    fn vertex_forms() {
+        // A position, texture coordinates and a normal, counted from the end of each list when
+        // negative, and a quad split into two triangles.
+        let shapes = meshes(&format!("{}f 1/1/1 2/2/1 -2/-1/-1 4/2/2\n", VERTICES));
+        match shapes[0] {
+            Shape::Mesh(ref mesh) => {
+                assert_eq!(mesh.faces, vec![(0, 1, 2), (0, 2, 3)]);
+                assert_eq!(mesh.vertices[2].y, 1.0);
+                assert_eq!(mesh.uvs, vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 0.0)]);
+                assert_eq!(mesh.normals.iter().map(|n| n.z).collect::<Vec<_>>(),
+                           vec![1.0, 1.0, -1.0, -1.0]);
+            }
+            _ => panic!("expected a mesh"),
+        }
+
+        // Without texture coordinates or normals on every vertex, the mesh has none.
+        let faces = [("f 1//1 2//1 3//1\n", false, true),
+                     ("f 1/1 2/2 3/3\n", true, false),
+                     ("f 1 2 3\n", false, false),
+                     ("f 1/1/1 2/2 3//1\n", false, false)];
+        for &(face, uvs, normals) in faces.iter() {
+            match meshes(&format!("{}{}", VERTICES, face))[0] {
+                Shape::Mesh(ref mesh) => {
+                    assert_eq!(!mesh.uvs.is_empty(), uvs, "{}", face);
+                    assert_eq!(!mesh.normals.is_empty(), normals, "{}", face);
+                }
+                _ => panic!("expected a mesh"),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_indices() {
+        assert_eq!(error(&format!("{}f 0 1 2\n", VERTICES)), "test.obj:10: index 0 out of range");
+        assert_eq!(error(&format!("{}f 1 2 5\n", VERTICES)), "test.obj:10: index 5 out of range");
+        assert_eq!(error(&format!("{}f 1 2 -5\n", VERTICES)),
+                   "test.obj:10: index -5 out of range");
+        assert_eq!(error(&format!("{}f 1/4 2/1 3/1\n", VERTICES)),
+                   "test.obj:10: index 4 out of range");
+        assert_eq!(error(&format!("{}f 1//3 2//1 3//1\n", VERTICES)),
+                   "test.obj:10: index 3 out of range");
+        assert_eq!(error(&format!("{}f 1 2\n", VERTICES)),
+                   "test.obj:10: expected at least three vertices");
+    }
+
+    fn material(text: &str) -> Material {
+        let mut materials = HashMap::new();
+        parse_mtl(text.as_bytes(),
+                  Path::new("test.mtl"),
+                  &mut materials,
+                  &mut Library::new())
+            .unwrap();
+        materials.remove("m").unwrap()
+    }
+
+    #[test]
+    fn illum() {
+        let surface = |text: &str| {
+            material(&format!("newmtl m\nKd 0.5 0.5 0.5\n{}", text)).surface()
+        };
+
+        match surface("illum 1\n") {
+            (_, c, Refl::Diff) => assert_eq!(c.x, 0.5),
+            _ => panic!("illum 1 is diffuse"),
+        }
+
+        match surface("Ks 0.5 0.5 0.5\nillum 2\n") {
+            (_, c, Refl::Mix(_, _, _)) => assert_eq!(c.x, 1.0),
+            _ => panic!("illum 2 with Kd and Ks mixes diffuse and specular"),
+        }
+
+        match surface("Ks 0.2 0.2 0.2\nillum 3\n") {
+            (_, c, Refl::Spec) => assert_eq!(c.x, 0.2),
+            _ => panic!("illum 3 is a mirror"),
+        }
+
+        for text in ["illum 7\nNi 1.3\n", "d 0.5\nNi 1.3\n", "Tr 0.5\nNi 1.3\n"].iter() {
+            match surface(text) {
+                (_, _, Refl::Refr(ior, _)) => assert_eq!(ior, 1.3),
+                _ => panic!("{} is glass", text),
+            }
+        }
+
+        match surface("Pr 0.3\nPm 1\n") {
+            (_, _, Refl::Principled(_)) => {}
+            _ => panic!("a roughness makes a principled material"),
+        }
+
+        let (e, _, _) = surface("Ke 1 2 3\n");
+        assert_eq!((e.x, e.y, e.z), (1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn emission_map() {
+        // The map lights the surface at full strength unless Ke, before or after it, says how
+        // bright it is.
+        let mut m = material("newmtl m\n");
+        m.textures.emission = Some(0);
+        assert_eq!(m.surface().0.x, 1.0);
+        m.ke = Vector::new(0.5, 0.5, 0.5);
+        assert_eq!(m.surface().0.x, 0.5);
+    }
+}
@@ -1,226 +1,386 @@
 #![allow(non_snake_case)]
-use api::{Ray, Refl, Sphere, Vector};
+use api::{Medium, Ray, Shape, Sphere, Vector};
+use bsdf::{Surface, basis, power_heuristic};
+use medium;
 use rand::Rng;
-use scene::Hit;
+use scene::World;
 use std::f64;
 
-fn diffuse<R: Rng>(_depth: i32,
-                   Xi: &mut R,
-                   pos: Vector,
-                   dir: Vector,
-                   norm: Vector,
-                   cast: &mut FnMut(f64, Ray)) {
-    let nl = if norm.dot(dir) < 0.0 {
-        norm
-    } else {
-        norm * -1.0
-    };
+fn light_sphere(world: &World, light: usize) -> &Sphere {
+    match world.session.scene[light] {
+        Shape::Sphere(ref s) => s,
+        _ => unreachable!(),
+    }
+}
 
-    let r1 = 2.0 * f64::consts::PI * Xi.next_f64();
-    let r2 = Xi.next_f64();
-    let r2s = r2.sqrt();
-    let w = nl;
-    let u = (if w.x.abs() > 0.1 {
-                Vector::new(0.0, 1.0, 0.0)
-            } else {
-                Vector::new(1.0, 0.0, 0.0)
-            })
-            .cross(w)
-            .norm();
-    let v = w.cross(u);
-    let d = (u * r1.cos() * r2s + v * r1.sin() * r2s + w * (1.0 - r2).sqrt()).norm();
-    cast(1.0, Ray::new(pos, d))
+// Something that sample_light aims at: an emissive sphere, one of the session's lights, the
+// environment map or sky, or the sun.
+#[derive(Copy, Clone)]
+enum Light {
+    Sphere(usize),
+    Delta(usize),
+    Environment,
+    Sun,
 }
 
-fn specular<R: Rng>(_depth: i32,
-                    _Xi: &mut R,
-                    pos: Vector,
-                    dir: Vector,
-                    norm: Vector,
-                    cast: &mut FnMut(f64, Ray)) {
-    cast(1.0, Ray::new(pos, dir - norm * 2.0 * norm.dot(dir)))
+fn light_count(world: &World) -> usize {
+    world.lights.len() + world.session.lights.len() + world.sampler().map_or(0, |_| 1) +
+    world.sun().map_or(0, |_| 1)
 }
 
-fn glossy_refraction<R: Rng>(depth: i32,
-                             Xi: &mut R,
-                             pos: Vector,
-                             dir: Vector,
-                             norm: Vector,
-                             cast: &mut FnMut(f64, Ray)) {
-    let refl_ray = Ray::new(pos, dir - norm * 2.0 * norm.dot(dir));
+// Picks a direction uniformly within the cone around `axis` in which the cosine of the angle from
+// `axis` is above `cos_max`.
+fn sample_cone<R: Rng>(Xi: &mut R, axis: Vector, cos_max: f64) -> Vector {
+    let (su, sv) = basis(axis);
+    let eps1 = Xi.next_f64();
+    let eps2 = Xi.next_f64();
+    let cos_a = 1.0 - eps1 + eps1 * cos_max;
+    let sin_a = (1.0 - cos_a * cos_a).sqrt();
+    let phi = 2.0 * f64::consts::PI * eps2;
+    (su * phi.cos() * sin_a + sv * phi.sin() * sin_a + axis * cos_a).norm()
+}
 
-    let nl = if norm.dot(dir) < 0.0 {
-        norm
-    } else {
-        norm * -1.0
-    };
+fn cone_pdf(cos_max: f64) -> f64 {
+    1.0 / (2.0 * f64::consts::PI * (1.0 - cos_max))
+}
 
-    let into = norm.dot(nl) > 0.0;
-    let nc = 1.0;
-    let nt = 1.5;
-    let nnt = if into {
-        nc / nt
+// Cosine of the half-angle of the cone that sphere `light` subtends from `pos`, or None from
+// inside the sphere.
+fn sphere_cone(world: &World, light: usize, pos: Vector) -> Option<f64> {
+    let s = light_sphere(world, light);
+    let sw = s.p - pos;
+    let dist2 = sw.dot(sw);
+    if dist2 <= s.rad * s.rad {
+        None
     } else {
-        nt / nc
-    };
-    let ddn = dir.dot(nl);
-    let cos2t = 1.0 - nnt * nnt * (1.0 - ddn * ddn);
-    if cos2t < 0.0 {
-        cast(1.0, refl_ray);
-    } else {
-        let tdir = (dir * nnt -
-                    norm *
-                    ((if into {
-                       1.0
-                   } else {
-                       -1.0
-                   }) * (ddn * nnt + cos2t.sqrt())))
-                       .norm();
-        let trans_ray = Ray::new(pos, tdir);
-        let a = nt - nc;
-        let b = nt + nc;
-        let R0 = a * a / (b * b);
-        let c = 1.0 -
-                (if into {
-            -ddn
-        } else {
-            tdir.dot(norm)
-        });
-        let Re = R0 + (1.0 - R0) * c * c * c * c * c;
-        let Tr = 1.0 - Re;
-        let P = 0.25 * 0.5 * Re;
-        let RP = Re / P;
-        let TP = Tr / (1.0 - P);
-        if depth > 2 {
-            if Xi.next_f64() < P {
-                cast(RP, refl_ray);
-            } else {
-                cast(TP, trans_ray);
-            }
-        } else {
-            cast(Re, refl_ray);
-            cast(Tr, trans_ray);
-        }
+        Some((1.0 - s.rad * s.rad / dist2).sqrt())
     }
 }
 
-fn _refraction<R: Rng>(_depth: i32,
-                       _Xi: &mut R,
-                       pos: Vector,
-                       dir: Vector,
-                       norm: Vector,
-                       cast: &mut FnMut(f64, Ray)) {
-    let nl = if norm.dot(dir) < 0.0 {
-        norm
-    } else {
-        norm * -1.0
+// Pdf, per unit solid angle, with which sample_light picks direction `dir` from `pos` towards
+// `light`.
+fn light_pdf(world: &World, light: Light, pos: Vector, dir: Vector) -> f64 {
+    let pdf = match light {
+        Light::Sphere(shape) => sphere_cone(world, shape, pos).map_or(0.0, cone_pdf),
+        Light::Delta(_) => 0.0,
+        Light::Environment => world.sampler().map_or(0.0, |sampler| sampler.pdf(dir)),
+        Light::Sun => {
+            match world.sun() {
+                Some(sun) if dir.dot(sun.dir) >= sun.cos_max => cone_pdf(sun.cos_max),
+                _ => 0.0,
+            }
+        }
     };
 
-    let into = norm.dot(nl) > 0.0;
-    let nc = 1.0;
-    let nt = 1.5;
-    let nnt = if into {
-        nc / nt
+    pdf / light_count(world) as f64
+}
+
+// Picks a light at random and a direction from `pos` towards it: within the cone that a sphere or
+// the sun subtends, in proportion to the brightness of the environment, or straight at a light
+// with no area. Returns the light, the direction and its pdf, which for a light with no area is
+// only the probability of picking it.
+fn sample_light<R: Rng>(world: &World, Xi: &mut R, pos: Vector) -> Option<(Light, Vector, f64)> {
+    let count = light_count(world);
+    if count == 0 {
+        return None;
+    }
+
+    let i = ((Xi.next_f64() * count as f64) as usize).min(count - 1);
+    let spheres = world.lights.len();
+    let deltas = world.session.lights.len();
+    let (light, l) = if i < spheres {
+        let light = world.lights[i];
+        let cos_a_max = match sphere_cone(world, light, pos) {
+            Some(cos_a_max) => cos_a_max,
+            None => return None,
+        };
+
+        let sw = (light_sphere(world, light).p - pos).norm();
+        (Light::Sphere(light), sample_cone(Xi, sw, cos_a_max))
+    } else if i < spheres + deltas {
+        let (l, _, _) = world.session.lights[i - spheres].illuminate(pos);
+        return Some((Light::Delta(i - spheres), l, 1.0 / count as f64));
+    } else if i == spheres + deltas && world.sampler().is_some() {
+        let (l, pdf) = world.sampler().unwrap().sample(Xi);
+        return Some((Light::Environment, l, pdf / count as f64));
     } else {
-        nt / nc
+        let sun = world.sun().unwrap();
+        (Light::Sun, sample_cone(Xi, sun.dir, sun.cos_max))
     };
-    let ddn = dir.dot(nl);
-    let cos2t = 1.0 - nnt * nnt * (1.0 - ddn * ddn);
-    if cos2t < 0.0 {
-        cast(1.0, Ray::new(pos, dir - norm * 2.0 * norm.dot(dir)));
-    } else {
-        let tdir = (dir * nnt -
-                    norm *
-                    ((if into {
-                       1.0
-                   } else {
-                       -1.0
-                   }) * (ddn * nnt + cos2t.sqrt())))
-                       .norm();
-        cast(1.0, Ray::new(pos, tdir));
-    }
+
+    Some((light, l, light_pdf(world, light, pos, l)))
 }
 
-fn material<R: Rng>(refl: &Refl,
-                    depth: i32,
-                    Xi: &mut R,
-                    pos: Vector,
-                    norm: Vector,
-                    dir: Vector,
-                    cast: &mut FnMut(f64, Ray)) {
-    match refl {
-        &Refl::Diff => diffuse(depth, Xi, pos, norm, dir, cast),
-        &Refl::Spec => specular(depth, Xi, pos, norm, dir, cast),
-        &Refl::Refr => glossy_refraction(depth, Xi, pos, norm, dir, cast),
-        &Refl::Mix(factor, ref r1, ref r2) => {
-            material(&*r1,
-                     depth,
-                     Xi,
-                     pos,
-                     norm,
-                     dir,
-                     &mut |scale, ray| cast((1.0 - factor) * scale, ray));
-            material(&*r2,
-                     depth,
-                     Xi,
-                     pos,
-                     norm,
-                     dir,
-                     &mut |scale, ray| cast(factor * scale, ray));
+// The media that a path is inside, innermost last: the shapes it has passed into, each with its
+// index of refraction and the medium filling it.
+type Media = Vec<(usize, f64, Medium)>;
+
+const VACUUM: Medium = Medium {
+    absorption: Vector::zero(),
+    scattering: Vector::zero(),
+    g: 0.0,
+    grid: None,
+};
+
+// The medium a path is travelling through, and the stretch of `ray` up to `dist` that lies in it:
+// the medium of the innermost shape the path is inside, or the fog within its box.
+fn medium(world: &World, media: &Media, ray: Ray, dist: f64) -> Option<(Medium, f64, f64)> {
+    match media.last() {
+        Some(&(_, _, medium)) => Some((medium, 0.0, dist)),
+        None => {
+            world.session.fog.and_then(|fog| match fog.bounds {
+                Some((lower, upper)) => {
+                    medium::clip(lower, upper, ray, dist).map(|(t0, t1)| (fog.medium, t0, t1))
+                }
+                None => Some((fog.medium, 0.0, dist)),
+            })
         }
     }
 }
 
-fn min_by_float_key<T: Iterator<Item = U>, U, F: Fn(&U) -> f64>(iter: &mut T, f: F) -> Option<U> {
-    iter.fold(None, |min_opt, item| {
-            let key = f(&item);
-            match min_opt {
-                Some((min_key, _)) if min_key < key => min_opt,
-                _ => Some((key, item)),
-            }
-        })
-        .map(|(_, min_item)| min_item)
+// Updates the media a path is inside as it passes through the surface of `shape` into or out of
+// an interior with index of refraction `ior`.
+fn pass(media: &mut Media, shape: usize, entering: bool, ior: f64, interior: Medium) {
+    if entering {
+        media.push((shape, ior, interior));
+    } else if let Some(i) = media.iter().rposition(|&(s, _, _)| s == shape) {
+        media.remove(i);
+    }
 }
 
-fn intersect(scene: &[Sphere], ray: Ray) -> Option<Hit> {
-    let mut hits = scene.iter().filter_map(|s| s.intersect(ray));
-    min_by_float_key(&mut hits, |&(t, _)| t).map(|(_, hit)| hit)
+// Light arriving at `pos` from direction `dir`, if it comes from `light` unobstructed, after
+// passing through the media on the way. Shadow rays pass through shapes that only bound a medium.
+fn visible<R: Rng>(world: &World,
+                   Xi: &mut R,
+                   pos: Vector,
+                   dir: Vector,
+                   light: Light,
+                   media: &Media)
+                   -> Option<Vector> {
+    let mut media = media.clone();
+    let mut ray = Ray::new(pos, dir);
+    let mut tr = Vector::one();
+    let mut dist = match light {
+        Light::Delta(i) => world.session.lights[i].illuminate(pos).1,
+        _ => f64::INFINITY,
+    };
+
+    loop {
+        let nearest = world.intersect(ray);
+        let t = nearest.as_ref().map_or(f64::INFINITY, |&(t, _, _)| t);
+        if let Some((medium, t0, t1)) = medium(world, &media, ray, t.min(dist)) {
+            let span = Ray::new(ray.o + ray.d * t0, ray.d);
+            let length = t1 - t0;
+            tr *= match medium.grid {
+                Some(i) => medium.estimate_transmittance(&world.session.grids[i], Xi, span, length),
+                None => medium.transmittance(length),
+            };
+        }
+
+        match (nearest, light) {
+            (Some((t, shape, hit)), _) if t < dist && world.bsdf(shape).is_pass_through() => {
+                let entering = dir.dot(hit.geo_norm) < 0.0;
+                let interior = world.session.scene[shape].medium().unwrap_or(VACUUM);
+                pass(&mut media, shape, entering, 1.0, interior);
+                ray = Ray::new(hit.pos, dir);
+                dist -= t;
+            }
+            (Some((t, _, _)), Light::Delta(_)) if t < dist => return None,
+            (_, Light::Delta(i)) => return Some(world.session.lights[i].illuminate(pos).2 * tr),
+            (Some((_, shape, hit)), Light::Sphere(s)) if shape == s => {
+                return Some(world.emission(shape, &hit) * tr)
+            }
+            (None, Light::Environment) => return Some(world.background(dir) * tr),
+            (None, Light::Sun) => return world.sun().map(|sun| sun.radiance(dir) * tr),
+            _ => return None,
+        }
+    }
 }
 
-pub fn radiance<R: Rng>(scene: &[Sphere], ray: Ray, depth: i32, Xi: &mut R) -> Vector {
+pub fn radiance<R: Rng>(world: &World, ray: Ray, depth: i32, Xi: &mut R) -> Vector {
     let mut result = Vector::zero();
     let mut work = Vec::new();
-    work.push((Vector::new(1.0, 1.0, 1.0), ray, depth));
-    while let Some((scale, ray, depth)) = work.pop() {
-        let hit = match intersect(scene, ray) {
+
+    // Each path carries its throughput, the pdfs of its distances through media for each channel
+    // relative to their mean, the ray, the bounce count, the pdf of the ray's direction (None for
+    // a delta lobe), where that direction was chosen, and the media it is inside.
+    work.push((Vector::one(), Vector::one(), ray, depth, None, ray.o, Vec::new()));
+    while let Some((scale, ratio, ray, depth, pdf, from, media)) = work.pop() {
+        let nearest = world.intersect(ray);
+        let dist = nearest.as_ref().map_or(f64::INFINITY, |&(t, _, _)| t);
+
+        // In a medium that scatters, the path may scatter before it reaches the surface.
+        let (scale, ratio) = match medium(world, &media, ray, dist) {
+            Some((medium, t0, t1)) if medium.scatters() || medium.grid.is_some() => {
+                let span = Ray::new(ray.o + ray.d * t0, ray.d);
+                let sampled = match medium.grid {
+                    Some(i) => medium.track(&world.session.grids[i], Xi, span, t1 - t0, ratio),
+                    None => medium.sample_distance(Xi, t1 - t0, ratio),
+                };
+
+                match sampled {
+                    (Some(t), weight, ratio) => {
+                        let pos = span.o + span.d * t;
+                        let scale = scale * weight;
+                        let depth = depth + 1;
+
+                        // The weight already includes the medium's albedo, which plays the part
+                        // of a surface's colour. It can exceed one in some channels.
+                        let scale = if depth > 5 {
+                            let p = scale.x.max(scale.y).max(scale.z).min(1.0);
+                            if Xi.next_f64() >= p {
+                                continue;
+                            }
+
+                            scale / p
+                        } else {
+                            scale
+                        };
+
+                        // Shadow rays cannot leave through a surface that refracts, such as that
+                        // of a subsurface material, so inside one there is no light sampling and
+                        // light reached by the phase function counts in full.
+                        let enclosed = media.iter()
+                                            .any(|&(s, _, _)| !world.bsdf(s).is_pass_through());
+                        if !enclosed {
+                            if let Some((light, l, light_pdf)) = sample_light(world, Xi, pos) {
+                                let phase = medium.phase(ray.d, l);
+                                let weight = match light {
+                                    Light::Delta(_) => 1.0,
+                                    _ => power_heuristic(light_pdf, phase),
+                                };
+
+                                if let Some(emit) = visible(world, Xi, pos, l, light, &media) {
+                                    result += scale * emit * (phase * weight / light_pdf);
+                                }
+                            }
+                        }
+
+                        let (l, pdf) = medium.sample_phase(Xi, ray.d);
+                        let pdf = if enclosed {
+                            None
+                        } else {
+                            Some(pdf)
+                        };
+
+                        work.push((scale, ratio, Ray::new(pos, l), depth, pdf, pos, media));
+                        continue;
+                    }
+                    (None, weight, ratio) => (scale * weight, ratio),
+                }
+            }
+            Some((medium, t0, t1)) => (scale * medium.transmittance(t1 - t0), ratio),
+            None => (scale, ratio),
+        };
+
+        let (_, shape, mut hit) = match nearest {
             Some(hit) => hit,
             None => {
+                // Light from outside the scene is weighted against sample_light in the same way
+                // as light from spheres.
+                let weight = |light| {
+                    match pdf {
+                        Some(pdf) => power_heuristic(pdf, light_pdf(world, light, from, ray.d)),
+                        None => 1.0,
+                    }
+                };
+
+                result += scale * world.background(ray.d) * weight(Light::Environment);
+                if let Some(sun) = world.sun() {
+                    result += scale * sun.radiance(ray.d) * weight(Light::Sun);
+                }
+
                 continue;
             }
         };
 
+        // `media` lists the refractive shapes that the path is inside, innermost last. The medium
+        // on the far side of this surface is the innermost one other than this shape.
+        let nc = media.iter()
+                      .rev()
+                      .find(|&&(s, _, _)| s != shape)
+                      .map_or(1.0, |&(_, ior, _)| ior);
+        let entering = ray.d.dot(hit.geo_norm) < 0.0;
+        let interior = world.session.scene[shape].medium();
+
+        // The boundary of a medium changes nothing else about the path, which carries on from
+        // where it last scattered.
+        if world.bsdf(shape).is_pass_through() {
+            let mut media = media;
+            pass(&mut media, shape, entering, nc, interior.unwrap_or(VACUUM));
+            work.push((scale, ratio, Ray::new(hit.pos, ray.d), depth, pdf, from, media));
+            continue;
+        }
+
+        world.shade(shape, ray, &mut hit);
         let depth = depth + 1;
-        let color = scale * hit.color;
-        result += scale * hit.emit;
 
-        let color = if depth > 5 {
-            let p = color.x.max(color.y).max(color.z);
+        // Light reached through a non-delta lobe could also have been found by sample_light, so
+        // it is weighted against that.
+        match pdf {
+            Some(pdf) if world.is_light(shape) => {
+                let light_pdf = light_pdf(world, Light::Sphere(shape), from, ray.d);
+                result += scale * hit.emit * power_heuristic(pdf, light_pdf);
+            }
+            _ => result += scale * hit.emit,
+        }
+
+        let scale = if depth > 5 {
+            let color = scale * hit.color;
+            let p = color.x.max(color.y).max(color.z).min(1.0);
             if Xi.next_f64() >= p {
                 continue;
             }
 
-            color / p
+            scale / p
         } else {
-            color
+            scale
         };
 
-        material(&hit.refl,
-                 depth,
-                 Xi,
-                 hit.pos,
-                 ray.d,
-                 hit.norm,
-                 &mut |scale, ray| work.push((color * scale, ray, depth)));
+        let bsdf = world.bsdf(shape);
+        let surface = Surface {
+            pos: hit.pos,
+            dir: ray.d,
+            norm: hit.norm,
+            nc: nc,
+            color: hit.color,
+            roughness: hit.roughness,
+            uv: hit.uv,
+            textures: &world.session.textures,
+        };
+
+        if !bsdf.is_delta() {
+            if let Some((light, l, light_pdf)) = sample_light(world, Xi, hit.pos) {
+                // Following the BSDF can never reach a light with no area.
+                let select = match light {
+                    Light::Delta(_) => 0.0,
+                    _ => 1.0,
+                };
+
+                let weight = bsdf.light_weight(&surface, l, select, light_pdf);
+                if weight.x.max(weight.y).max(weight.z) > 0.0 {
+                    if let Some(emit) = visible(world, Xi, hit.pos, l, light, &media) {
+                        result += scale * emit * weight;
+                    }
+                }
+            }
+        }
+
+        // A shape's own medium takes the place of its material's, or of its absorption.
+        let dir = ray.d;
+        let geo_norm = hit.geo_norm;
+        let (inside, absorption) = bsdf.medium();
+        let interior = interior.or_else(|| bsdf.interior(hit.color))
+                               .unwrap_or(Medium { absorption: absorption, ..VACUUM });
+        bsdf.sample(depth, Xi, &surface, &mut |weight, ray, pdf| {
+            let transmitted = ray.d.dot(geo_norm) * dir.dot(geo_norm) > 0.0;
+            let mut media = media.clone();
+            if transmitted {
+                pass(&mut media, shape, entering, inside, interior);
+            }
+
+            work.push((scale * weight, ratio, ray, depth, pdf, ray.o, media))
+        });
     }
 
     result
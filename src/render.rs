@@ -1,6 +1,7 @@
-use api::{Vector, Ray, Rectangle, Session};
+use api::{Vector, Ray, Rectangle};
 use radiance;
 use rand::{Rng, SeedableRng, StdRng};
+use scene::World;
 
 fn clamp(x: f64) -> f64 {
     x.max(0.0).min(1.0)
@@ -10,7 +11,8 @@ fn to_int(x: f64) -> u8 {
     (clamp(x).powf(1.0 / 2.2) * 255.0 + 0.5) as u8
 }
 
-pub fn render<F: FnMut(Vec<u8>)>(xi: &mut StdRng, session: &Session, rect: Rectangle, mut tx: F) {
+pub fn render<F: FnMut(Vec<u8>)>(xi: &mut StdRng, world: &World, rect: Rectangle, mut tx: F) {
+    let session = &world.session;
     let w = session.width;
     let h = session.height;
     let samps = session.samples;
@@ -47,7 +49,7 @@ pub fn render<F: FnMut(Vec<u8>)>(xi: &mut StdRng, session: &Session, rect: Recta
                                 cam.d;
 
                         let ray = Ray::new(cam.o + d * 140.0, d.norm());
-                        *r += radiance::radiance(&session.scene, ray, 0, xi);
+                        *r += radiance::radiance(world, ray, 0, xi);
                     }
                 }
 
@@ -62,3 +64,27 @@ pub fn render<F: FnMut(Vec<u8>)>(xi: &mut StdRng, session: &Session, rect: Recta
         tx(image)
     }
 }
+
+pub fn tiles(width: usize, height: usize) -> Vec<Rectangle> {
+    let mut tiles = Vec::new();
+    let mut y = 0;
+    while y < height {
+        let mut x = 0;
+        while x < width {
+            tiles.push(Rectangle::new(x, y, (width - x).min(32), (height - y).min(32)));
+            x += 32;
+        }
+
+        y += 32;
+    }
+
+    tiles.sort_by_key(|tile| {
+        let tx = tile.left + tile.width / 2;
+        let ty = tile.top + tile.height / 2;
+        let dx = tx as isize - width as isize / 2;
+        let dy = ty as isize - height as isize / 2;
+        (dx * dx + dy * dy, tile.top, tile.left)
+    });
+
+    tiles
+}
@@ -1,4 +1,10 @@
-use api::{Vector, Ray, Refl, Sphere};
+use api::{Vector, Ray, Refl, Sphere, Triangle, Mesh, Shape, Session, Textures, Environment,
+          Medium};
+use bsdf::{Bsdf, basis};
+use bvh::Bvh;
+use environment::{self, Sampler};
+use sky::{Model, Sun};
+use std::f64;
 use std::ops::{Add, AddAssign, Sub, SubAssign, Mul, MulAssign, Div, DivAssign};
 
 impl Vector {
@@ -9,6 +15,14 @@ impl Vector {
             z: 0.0,
         }
     }
+
+    pub const fn one() -> Self {
+        Vector {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        }
+    }
 }
 
 impl Add for Vector {
@@ -107,12 +121,21 @@ impl Vector {
     }
 }
 
+// `geo_norm` is the true normal of the surface, which decides which side of it a ray is on, while
+// `norm` is the normal for shading, which may be interpolated or perturbed by a texture.
 pub struct Hit<'a> {
     pub pos: Vector,
     pub norm: Vector,
+    pub geo_norm: Vector,
     pub emit: Vector,
     pub color: Vector,
     pub refl: &'a Refl,
+    pub uv: (f64, f64),
+    // Rates of change of position with u and v across the surface.
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    // Set when a roughness texture overrides the roughness of `refl`.
+    pub roughness: Option<f64>,
 }
 
 impl<'a> Hit<'a> {
@@ -120,14 +143,40 @@ impl<'a> Hit<'a> {
                      norm: Vector,
                      emit: Vector,
                      color: Vector,
-                     refl: &'a Refl)
+                     refl: &'a Refl,
+                     uv: (f64, f64),
+                     dpdu: Vector,
+                     dpdv: Vector)
                      -> Self {
         Hit {
             pos: pos,
             norm: norm,
+            geo_norm: norm,
             emit: emit,
             color: color,
             refl: refl,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            roughness: None,
+        }
+    }
+
+    // Tangent and bitangent, following u and v and orthonormal to the normal. Falls back on an
+    // arbitrary frame where u does not vary, such as at the poles of a sphere.
+    pub fn tangents(&self) -> (Vector, Vector) {
+        let n = self.norm;
+        let t = self.dpdu - n * n.dot(self.dpdu);
+        if t.dot(t) < 1e-20 {
+            return basis(n);
+        }
+
+        let t = t.norm();
+        let b = n.cross(t);
+        if b.dot(self.dpdv) < 0.0 {
+            (t, b * -1.0)
+        } else {
+            (t, b)
         }
     }
 }
@@ -159,6 +208,330 @@ impl Sphere {
 
         let x = ray.o + (ray.d * t);
         let n = (x - self.p).norm();
-        Some((t, Hit::new(x, n, self.e, self.c, &self.refl)))
+
+        // Spherical mapping, with v running from the bottom of the sphere to the top. u goes once
+        // round the equator and v from pole to pole, so those set the lengths of dpdu and dpdv.
+        let uv = (0.5 + n.z.atan2(n.x) / (2.0 * f64::consts::PI),
+                  0.5 + n.y.max(-1.0).min(1.0).asin() / f64::consts::PI);
+        let cos_lat = (n.x * n.x + n.z * n.z).sqrt();
+        let dpdu = Vector::new(-n.z, 0.0, n.x) * (2.0 * f64::consts::PI * self.rad);
+        let dpdv = if cos_lat > 0.0 {
+            Vector::new(-n.y * n.x / cos_lat, cos_lat, -n.y * n.z / cos_lat)
+        } else {
+            Vector::new(-n.y, 0.0, 0.0)
+        } * (f64::consts::PI * self.rad);
+        Some((t, Hit::new(x, n, self.e, self.c, &self.refl, uv, dpdu, dpdv)))
+    }
+}
+
+// Möller–Trumbore ray/triangle test. Returns the distance along the ray and the barycentric
+// coordinates of the hit relative to v1 and v2.
+fn intersect_triangle(ray: Ray, v0: Vector, v1: Vector, v2: Vector) -> Option<(f64, f64, f64)> {
+    let eps = 1e-4;
+    let e1 = v1 - v0;
+    let e2 = v2 - v0;
+    let p = ray.d.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.o - v0;
+    let u = s.dot(p) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(e1);
+    let v = ray.d.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = e2.dot(q) * inv_det;
+    if t > eps {
+        Some((t, u, v))
+    } else {
+        None
+    }
+}
+
+impl Triangle {
+    pub fn intersect(&self, ray: Ray) -> Option<(f64, Hit)> {
+        intersect_triangle(ray, self.v0, self.v1, self.v2).map(|(t, u, v)| {
+            let x = ray.o + (ray.d * t);
+            let e1 = self.v1 - self.v0;
+            let e2 = self.v2 - self.v0;
+            let n = e1.cross(e2).norm();
+            (t, Hit::new(x, n, self.e, self.c, &self.refl, (u, v), e1, e2))
+        })
+    }
+}
+
+impl Mesh {
+    pub fn intersect_face(&self, face: usize, ray: Ray) -> Option<(f64, Hit)> {
+        let (i0, i1, i2) = self.faces[face];
+        let v0 = self.vertices[i0];
+        let v1 = self.vertices[i1];
+        let v2 = self.vertices[i2];
+        intersect_triangle(ray, v0, v1, v2).map(|(t, u, v)| {
+            let x = ray.o + (ray.d * t);
+            let geo_norm = (v1 - v0).cross(v2 - v0).norm();
+            let n = if self.normals.is_empty() {
+                geo_norm
+            } else {
+                (self.normals[i0] * (1.0 - u - v) + self.normals[i1] * u + self.normals[i2] * v)
+                    .norm()
+            };
+
+            // The winding of a face need not agree with its normals, which say where outside is.
+            let geo_norm = if geo_norm.dot(n) < 0.0 {
+                geo_norm * -1.0
+            } else {
+                geo_norm
+            };
+
+            let e1 = v1 - v0;
+            let e2 = v2 - v0;
+            let (uv, dpdu, dpdv) = if self.uvs.is_empty() {
+                ((u, v), e1, e2)
+            } else {
+                let (uv0, uv1, uv2) = (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+                let uv = (uv0.0 * (1.0 - u - v) + uv1.0 * u + uv2.0 * v,
+                          uv0.1 * (1.0 - u - v) + uv1.1 * u + uv2.1 * v);
+
+                // Solve for the derivatives of position from the edges and their change in UV.
+                let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+                let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+                let det = du1 * dv2 - dv1 * du2;
+                if det.abs() < 1e-12 {
+                    (uv, e1, e2)
+                } else {
+                    (uv, (e1 * dv2 - e2 * dv1) / det, (e2 * du1 - e1 * du2) / det)
+                }
+            };
+
+            let hit = Hit::new(x, n, self.e, self.c, &self.refl, uv, dpdu, dpdv);
+            (t, Hit { geo_norm: geo_norm, ..hit })
+        })
+    }
+
+    pub fn intersect(&self, ray: Ray) -> Option<(f64, Hit)> {
+        let mut nearest: Option<(f64, Hit)> = None;
+        for face in 0..self.faces.len() {
+            if let Some((t, hit)) = self.intersect_face(face, ray) {
+                if nearest.as_ref().map_or(true, |&(t0, _)| t < t0) {
+                    nearest = Some((t, hit));
+                }
+            }
+        }
+
+        nearest
+    }
+}
+
+impl Shape {
+    pub fn intersect(&self, ray: Ray) -> Option<(f64, Hit)> {
+        match self {
+            &Shape::Sphere(ref sphere) => sphere.intersect(ray),
+            &Shape::Triangle(ref triangle) => triangle.intersect(ray),
+            &Shape::Mesh(ref mesh) => mesh.intersect(ray),
+        }
+    }
+
+    pub fn refl(&self) -> &Refl {
+        match self {
+            &Shape::Sphere(ref sphere) => &sphere.refl,
+            &Shape::Triangle(ref triangle) => &triangle.refl,
+            &Shape::Mesh(ref mesh) => &mesh.refl,
+        }
+    }
+
+    pub fn textures(&self) -> Textures {
+        match self {
+            &Shape::Sphere(ref sphere) => sphere.textures,
+            &Shape::Triangle(ref triangle) => triangle.textures,
+            &Shape::Mesh(ref mesh) => mesh.textures,
+        }
+    }
+
+    pub fn medium(&self) -> Option<Medium> {
+        match self {
+            &Shape::Sphere(ref sphere) => sphere.medium,
+            &Shape::Triangle(_) => None,
+            &Shape::Mesh(ref mesh) => mesh.medium,
+        }
+    }
+}
+
+// A session together with the acceleration structures and materials built from it, shared
+// read-only by the rendering threads.
+pub struct World {
+    pub session: Session,
+    pub lights: Vec<usize>,
+    bvh: Bvh,
+    bsdfs: Vec<Box<Bsdf>>,
+    sky: Option<Model>,
+    sun: Option<Sun>,
+    sampler: Option<Sampler>,
+}
+
+impl World {
+    pub fn new(session: Session) -> Self {
+        let bvh = Bvh::new(&session.scene);
+
+        // Emissive spheres are sampled directly by the integrator; other emissive shapes are
+        // only found by chance.
+        let lights = session.scene
+                            .iter()
+                            .enumerate()
+                            .filter_map(|(i, shape)| match shape {
+                                &Shape::Sphere(ref s) if s.e.x + s.e.y + s.e.z > 0.0 => Some(i),
+                                _ => None,
+                            })
+                            .collect();
+
+        let bsdfs = session.scene.iter().map(|shape| shape.refl().bsdf()).collect();
+
+        // The sky is sampled through a coarse table of its brightness, and the sun on its own.
+        let (sky, sun, sampler) = match session.environment {
+            Some(Environment::Map(ref map)) => (None, None, Sampler::new(&map.image, map.rotation)),
+            Some(Environment::Sky(ref sky)) => {
+                let model = Model::new(sky);
+                let sampler = Sampler::new(&environment::bake(128, 64, |dir| model.radiance(dir)),
+                                           0.0);
+                let sun = model.sun(sky);
+                (Some(model), sun, sampler)
+            }
+            None => (None, None, None),
+        };
+
+        World {
+            session: session,
+            lights: lights,
+            bvh: bvh,
+            bsdfs: bsdfs,
+            sky: sky,
+            sun: sun,
+            sampler: sampler,
+        }
+    }
+
+    // Light arriving from outside the scene in direction `dir`, other than from the sun.
+    pub fn background(&self, dir: Vector) -> Vector {
+        if let Some(ref sky) = self.sky {
+            return sky.radiance(dir);
+        }
+
+        match self.session.environment {
+            Some(Environment::Map(ref map)) => map.radiance(dir),
+            _ => Vector::zero(),
+        }
+    }
+
+    // The distribution used to sample the background, if it gives off any light.
+    pub fn sampler(&self) -> Option<&Sampler> {
+        self.sampler.as_ref()
+    }
+
+    pub fn sun(&self) -> Option<&Sun> {
+        self.sun.as_ref()
+    }
+
+    pub fn bsdf(&self, shape: usize) -> &Bsdf {
+        &*self.bsdfs[shape]
+    }
+
+    pub fn is_light(&self, shape: usize) -> bool {
+        self.lights.binary_search(&shape).is_ok()
+    }
+
+    // The normal at a hit after applying a normal map, whose red, green and blue channels hold the
+    // normal along the tangent, bitangent and original normal, and then a bump map.
+    fn perturb(&self, hit: &Hit, textures: Textures) -> Vector {
+        let mut norm = hit.norm;
+        let (mut t, mut b) = hit.tangents();
+        if let Some(i) = textures.normal {
+            let m = self.session.textures[i].lookup(hit.uv, hit.pos) * 2.0 - Vector::one();
+            norm = (t * m.x + b * m.y + norm * m.z).norm();
+            t = (t - norm * norm.dot(t)).norm();
+            b = if norm.cross(t).dot(b) < 0.0 {
+                norm.cross(t) * -1.0
+            } else {
+                norm.cross(t)
+            };
+        }
+
+        // As in pbrt, the height is differenced over a small step in u and in v, which moves the
+        // position along dpdu and dpdv to match, and divided by the distance moved to give slopes
+        // per unit length across the surface.
+        if let Some(i) = textures.bump {
+            let eps = 1e-3;
+            let texture = &self.session.textures[i];
+            let height = |uv: (f64, f64), pos: Vector| {
+                let h = texture.lookup(uv, pos);
+                (h.x + h.y + h.z) / 3.0
+            };
+
+            let h = height(hit.uv, hit.pos);
+            let slope = |uv: (f64, f64), dpd: Vector| {
+                let length = dpd.dot(dpd).sqrt() * eps;
+                if length > 0.0 {
+                    (height(uv, hit.pos + dpd * eps) - h) / length
+                } else {
+                    0.0
+                }
+            };
+
+            let dhdu = slope((hit.uv.0 + eps, hit.uv.1), hit.dpdu);
+            let dhdv = slope((hit.uv.0, hit.uv.1 + eps), hit.dpdv);
+            norm = (norm - (t * dhdu + b * dhdv) * textures.bump_scale).norm();
+        }
+
+        norm
+    }
+
+    // The nearest hit along `ray`, as the shape's geometry gives it. Shadow rays need no more;
+    // paths that go on from the hit pass it through `shade`.
+    pub fn intersect(&self, ray: Ray) -> Option<(f64, usize, Hit)> {
+        self.bvh.intersect(&self.session.scene, ray)
+    }
+
+    // Light given off at a hit, after the shape's emission texture.
+    pub fn emission(&self, shape: usize, hit: &Hit) -> Vector {
+        match self.session.scene[shape].textures().emission {
+            Some(i) => hit.emit * self.session.textures[i].lookup(hit.uv, hit.pos),
+            None => hit.emit,
+        }
+    }
+
+    // Applies the shape's textures to a hit by `ray`.
+    pub fn shade(&self, shape: usize, ray: Ray, hit: &mut Hit) {
+        let textures = self.session.scene[shape].textures();
+        if let Some(i) = textures.color {
+            hit.color *= self.session.textures[i].lookup(hit.uv, hit.pos);
+        }
+
+        hit.emit = self.emission(shape, hit);
+        if let Some(i) = textures.roughness {
+            let r = self.session.textures[i].lookup(hit.uv, hit.pos);
+            hit.roughness = Some((r.x + r.y + r.z) / 3.0);
+        }
+
+        // A shading normal that faces the other way from the surface would confuse which side of
+        // it the ray is on.
+        let geo_norm = hit.geo_norm;
+        let faces = |norm: Vector| norm.dot(ray.d) * geo_norm.dot(ray.d) > 0.0;
+        if !faces(hit.norm) {
+            hit.norm = geo_norm;
+        }
+
+        if textures.normal.is_some() || textures.bump.is_some() {
+            let norm = self.perturb(hit, textures);
+            if faces(norm) {
+                hit.norm = norm;
+            }
+        }
     }
 }
@@ -0,0 +1,899 @@
+use api::{Environment, EnvironmentMap, Fog, Grid, Light, LightKind, Medium, Mesh, Param, Pattern,
+          Principled, Procedural, Ray, Refl, Session, Shape, Sky, Sphere, Textures, Triangle,
+          Vector};
+use rustc_serialize::json::{self, Json, ParserError};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use ies;
+use obj;
+use sky;
+use std::path::Path;
+use super::AppError;
+use texture::{self, Library};
+use volume;
+
+type Object = BTreeMap<String, Json>;
+
+fn join(at: &str, name: &str) -> String {
+    if at.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", at, name)
+    }
+}
+
+fn error<T>(at: &str, message: &str) -> Result<T, AppError> {
+    Err(AppError::new(format!("{}: {}", at, message)))
+}
+
+fn object<'a>(json: &'a Json, at: &str) -> Result<&'a Object, AppError> {
+    match json.as_object() {
+        Some(obj) => Ok(obj),
+        None => error(at, "expected an object"),
+    }
+}
+
+fn array<'a>(json: &'a Json, at: &str) -> Result<&'a [Json], AppError> {
+    match json.as_array() {
+        Some(array) => Ok(array),
+        None => error(at, "expected an array"),
+    }
+}
+
+fn check_fields(obj: &Object, at: &str, allowed: &[&str]) -> Result<(), AppError> {
+    for name in obj.keys() {
+        if !allowed.contains(&name.as_str()) {
+            return error(&join(at, name), "unknown field");
+        }
+    }
+
+    Ok(())
+}
+
+fn required<T, F>(obj: &Object, at: &str, name: &str, f: F) -> Result<T, AppError>
+    where F: FnOnce(&Json, &str) -> Result<T, AppError>
+{
+    let at = join(at, name);
+    match obj.get(name) {
+        Some(json) => f(json, &at),
+        None => error(&at, "missing field"),
+    }
+}
+
+fn optional<T, F>(obj: &Object, at: &str, name: &str, default: T, f: F) -> Result<T, AppError>
+    where F: FnOnce(&Json, &str) -> Result<T, AppError>
+{
+    match obj.get(name) {
+        Some(json) => f(json, &join(at, name)),
+        None => Ok(default),
+    }
+}
+
+fn number(json: &Json, at: &str) -> Result<f64, AppError> {
+    match json.as_f64() {
+        Some(n) => Ok(n),
+        None => error(at, "expected a number"),
+    }
+}
+
+fn unit(json: &Json, at: &str) -> Result<f64, AppError> {
+    match json.as_f64() {
+        Some(n) if n >= 0.0 && n <= 1.0 => Ok(n),
+        _ => error(at, "expected a number from 0 to 1"),
+    }
+}
+
+fn count(json: &Json, at: &str) -> Result<usize, AppError> {
+    match json.as_u64() {
+        Some(n) if n > 0 => Ok(n as usize),
+        _ => error(at, "expected a positive integer"),
+    }
+}
+
+fn vector(json: &Json, at: &str) -> Result<Vector, AppError> {
+    let items = try!(array(json, at));
+    if items.len() != 3 {
+        return error(at, "expected an array of three numbers");
+    }
+
+    Ok(Vector::new(try!(number(&items[0], &format!("{}[0]", at))),
+                   try!(number(&items[1], &format!("{}[1]", at))),
+                   try!(number(&items[2], &format!("{}[2]", at)))))
+}
+
+fn string(json: &Json, at: &str) -> Result<String, AppError> {
+    match json.as_string() {
+        Some(s) => Ok(s.to_string()),
+        None => error(at, "expected a string"),
+    }
+}
+
+// A colour with every channel from 0 to 1.
+fn unit_color(json: &Json, at: &str) -> Result<Vector, AppError> {
+    let color = try!(vector(json, at));
+    if color.x.min(color.y).min(color.z) < 0.0 || color.x.max(color.y).max(color.z) > 1.0 {
+        return error(at, "expected numbers from 0 to 1");
+    }
+
+    Ok(color)
+}
+
+// A material parameter, either as `f` reads it or as a texture.
+fn param<T, F>(json: &Json,
+               at: &str,
+               base: &Path,
+               srgb: bool,
+               library: &mut Library,
+               f: F)
+               -> Result<Param<T>, AppError>
+    where F: FnOnce(&Json, &str) -> Result<T, AppError>
+{
+    match json {
+        &Json::String(_) | &Json::Object(_) => {
+            texture(json, at, base, srgb, library).map(Param::Texture)
+        }
+        _ => f(json, at).map(Param::Value),
+    }
+}
+
+fn refl(json: &Json, at: &str, base: &Path, library: &mut Library) -> Result<Refl, AppError> {
+    let empty = Object::new();
+    let (name, obj) = match json.as_string() {
+        Some(name) => (name.to_string(), &empty),
+        None => {
+            let obj = try!(object(json, at));
+            (try!(required(obj, at, "type", string)), obj)
+        }
+    };
+
+    match name.as_str() {
+        "diff" => {
+            try!(check_fields(obj, at, &["type", "sigma"]));
+            let sigma = try!(optional(obj, at, "sigma", Param::Value(0.0), |json, at| {
+                param(json, at, base, false, library, number)
+            }));
+
+            match sigma {
+                Param::Value(sigma) if sigma <= 0.0 => Ok(Refl::Diff),
+                sigma => Ok(Refl::OrenNayar(sigma)),
+            }
+        }
+        "spec" => {
+            try!(check_fields(obj, at, &["type"]));
+            Ok(Refl::Spec)
+        }
+        "null" => {
+            try!(check_fields(obj, at, &["type"]));
+            Ok(Refl::Null)
+        }
+        "refr" => {
+            try!(check_fields(obj, at, &["type", "ior", "absorption", "roughness"]));
+            let ior = try!(optional(obj, at, "ior", 1.5, number));
+            let absorption = try!(optional(obj, at, "absorption", Vector::zero(), vector));
+            let roughness = try!(optional(obj, at, "roughness", 0.0, number));
+            if roughness > 0.0 {
+                Ok(Refl::RoughRefr(roughness, ior, absorption))
+            } else {
+                Ok(Refl::Refr(ior, absorption))
+            }
+        }
+        "subsurface" => {
+            try!(check_fields(obj, at, &["type", "ior", "mean_free_path"]));
+            let ior = try!(optional(obj, at, "ior", 1.5, number));
+            let mfp = try!(required(obj, at, "mean_free_path", shade));
+            if mfp.x.min(mfp.y).min(mfp.z) <= 0.0 {
+                return error(&join(at, "mean_free_path"), "expected positive lengths");
+            }
+
+            Ok(Refl::Subsurface(ior, mfp))
+        }
+        "metal" => {
+            try!(check_fields(obj, at, &["type", "roughness", "eta", "k"]));
+            let roughness = try!(optional(obj, at, "roughness", Param::Value(0.1), |json, at| {
+                param(json, at, base, false, library, number)
+            }));
+
+            let eta = try!(required(obj, at, "eta", |json, at| {
+                param(json, at, base, false, library, vector)
+            }));
+
+            let k = try!(required(obj, at, "k", |json, at| {
+                param(json, at, base, false, library, vector)
+            }));
+
+            Ok(Refl::Metal(roughness, eta, k))
+        }
+        "principled" => {
+            try!(check_fields(obj,
+                              at,
+                              &["type", "base_color", "metallic", "roughness", "specular",
+                                "clearcoat", "sheen", "transmission"]));
+            let white = Param::Value(Vector::one());
+            let base_color = try!(optional(obj, at, "base_color", white, |json, at| {
+                param(json, at, base, true, library, unit_color)
+            }));
+
+            let mut unit_param = |name: &str, default: f64| {
+                optional(obj, at, name, Param::Value(default), |json, at| {
+                    param(json, at, base, false, library, unit)
+                })
+            };
+
+            Ok(Refl::Principled(Principled {
+                base_color: base_color,
+                metallic: try!(unit_param("metallic", 0.0)),
+                roughness: try!(unit_param("roughness", 0.5)),
+                specular: try!(unit_param("specular", 0.5)),
+                clearcoat: try!(unit_param("clearcoat", 0.0)),
+                sheen: try!(unit_param("sheen", 0.0)),
+                transmission: try!(unit_param("transmission", 0.0)),
+            }))
+        }
+        "coat" => {
+            try!(check_fields(obj, at, &["type", "ior", "roughness", "base"]));
+            let ior = try!(optional(obj, at, "ior", Param::Value(1.5), |json, at| {
+                param(json, at, base, false, library, number)
+            }));
+
+            let roughness = try!(optional(obj, at, "roughness", Param::Value(0.0), |json, at| {
+                param(json, at, base, false, library, number)
+            }));
+
+            let inner = try!(required(obj, at, "base", |json, at| refl(json, at, base, library)));
+            Ok(Refl::Coat(ior, roughness, Box::new(inner)))
+        }
+        "mix" => {
+            try!(check_fields(obj, at, &["type", "factor", "a", "b"]));
+            let factor = try!(required(obj, at, "factor", |json, at| {
+                param(json, at, base, false, library, unit)
+            }));
+
+            let a = try!(required(obj, at, "a", |json, at| refl(json, at, base, library)));
+            let b = try!(required(obj, at, "b", |json, at| refl(json, at, base, library)));
+            Ok(Refl::Mix(factor, Box::new(a), Box::new(b)))
+        }
+        _ => error(at, &format!("unknown material \"{}\"", name)),
+    }
+}
+
+fn index(json: &Json, at: &str) -> Result<usize, AppError> {
+    match json.as_u64() {
+        Some(n) => Ok(n as usize),
+        None => error(at, "expected a non-negative integer"),
+    }
+}
+
+fn face(json: &Json, at: &str) -> Result<(usize, usize, usize), AppError> {
+    let items = try!(array(json, at));
+    if items.len() != 3 {
+        return error(at, "expected an array of three vertex indices");
+    }
+
+    Ok((try!(index(&items[0], &format!("{}[0]", at))),
+        try!(index(&items[1], &format!("{}[1]", at))),
+        try!(index(&items[2], &format!("{}[2]", at)))))
+}
+
+fn uv(json: &Json, at: &str) -> Result<(f64, f64), AppError> {
+    let items = try!(array(json, at));
+    if items.len() != 2 {
+        return error(at, "expected an array of two numbers");
+    }
+
+    Ok((try!(number(&items[0], &format!("{}[0]", at))),
+        try!(number(&items[1], &format!("{}[1]", at)))))
+}
+
+// A colour, or a number for a shade of grey.
+fn shade(json: &Json, at: &str) -> Result<Vector, AppError> {
+    match json.as_f64() {
+        Some(n) => Ok(Vector::one() * n),
+        None => vector(json, at),
+    }
+}
+
+fn procedural(json: &Json, at: &str) -> Result<Procedural, AppError> {
+    let obj = try!(object(json, at));
+    let name = try!(required(obj, at, "type", string));
+    let fields = ["type", "space", "scale", "a", "b", "octaves"];
+    let pattern = match name.as_str() {
+        "checker" | "gradient" => {
+            try!(check_fields(obj, at, &fields[..5]));
+            if name == "checker" {
+                Pattern::Checker
+            } else {
+                Pattern::Gradient
+            }
+        }
+        "noise" | "marble" | "wood" => {
+            try!(check_fields(obj, at, &fields));
+            let octaves = try!(optional(obj, at, "octaves", 4, count));
+            match name.as_str() {
+                "noise" => Pattern::Noise(octaves),
+                "marble" => Pattern::Marble(octaves),
+                _ => Pattern::Wood(octaves),
+            }
+        }
+        _ => return error(at, &format!("unknown texture \"{}\"", name)),
+    };
+
+    let uv = match try!(optional(obj, at, "space", "uv".to_string(), string)).as_str() {
+        "uv" => true,
+        "position" => false,
+        _ => return error(&join(at, "space"), "expected \"uv\" or \"position\""),
+    };
+
+    Ok(Procedural {
+        pattern: pattern,
+        uv: uv,
+        scale: try!(optional(obj, at, "scale", 1.0, number)),
+        a: try!(optional(obj, at, "a", Vector::zero(), shade)),
+        b: try!(optional(obj, at, "b", Vector::one(), shade)),
+    })
+}
+
+// Either the path of an image, relative to the scene file, or a procedural texture.
+fn texture(json: &Json,
+           at: &str,
+           base: &Path,
+           srgb: bool,
+           library: &mut Library)
+           -> Result<usize, AppError> {
+    match json.as_string() {
+        Some(path) => {
+            library.load(&base.join(path), srgb)
+                   .map_err(|err| AppError::new(format!("{}: {}", at, err)))
+        }
+        None => Ok(library.add(try!(procedural(json, at)))),
+    }
+}
+
+// Textures for the colour, emission and roughness of a shape, and for its normal.
+fn textures(json: &Json,
+            at: &str,
+            base: &Path,
+            library: &mut Library)
+            -> Result<Textures, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj,
+                      at,
+                      &["color", "emission", "roughness", "normal", "bump", "bump_scale"]));
+    let mut slot = |name: &str, srgb: bool| {
+        optional(obj, at, name, None, |json, at| texture(json, at, base, srgb, library).map(Some))
+    };
+
+    Ok(Textures {
+        color: try!(slot("color", true)),
+        emission: try!(slot("emission", true)),
+        roughness: try!(slot("roughness", false)),
+        normal: try!(slot("normal", false)),
+        bump: try!(slot("bump", false)),
+        bump_scale: try!(optional(obj, at, "bump_scale", 1.0, number)),
+    })
+}
+
+// Emission, colour, material and textures, which are common to every kind of shape. A texture
+// scales the corresponding colour, which then defaults to white. So does the colour of a principled
+// material, which has a base colour of its own.
+fn surface(obj: &Object,
+           at: &str,
+           base: &Path,
+           library: &mut Library)
+           -> Result<(Vector, Vector, Refl, Textures), AppError> {
+    let textures = try!(optional(obj, at, "textures", Textures::none(), |json, at| {
+        textures(json, at, base, library)
+    }));
+
+    let default = |texture: Option<usize>| {
+        if texture.is_some() {
+            Vector::one()
+        } else {
+            Vector::zero()
+        }
+    };
+
+    let e = try!(optional(obj, at, "emission", default(textures.emission), vector));
+    let refl = try!(optional(obj, at, "material", Refl::Diff, |json, at| {
+        refl(json, at, base, library)
+    }));
+    let c = match refl {
+        Refl::Principled(_) => try!(optional(obj, at, "color", Vector::one(), vector)),
+        _ => try!(optional(obj, at, "color", default(textures.color), vector)),
+    };
+
+    if let Refl::Null = refl {
+        if e.x.max(e.y).max(e.z) > 0.0 {
+            return error(&join(at, "emission"), "a shape with no surface cannot give off light");
+        }
+    }
+
+    Ok((e, c, refl, textures))
+}
+
+// A voxel grid of densities, loaded from a file relative to the scene file and optionally moved to
+// fill a box other than its own.
+fn grid(json: &Json, at: &str, base: &Path) -> Result<Grid, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["path", "lower", "upper"]));
+    let path = base.join(try!(required(obj, at, "path", string)));
+    let grid = try!(volume::load(&path).map_err(|err| AppError::new(format!("{}: {}", at, err))));
+    let lower = try!(optional(obj, at, "lower", grid.lower, vector));
+    let upper = try!(optional(obj, at, "upper", grid.upper, vector));
+    if lower.x >= upper.x || lower.y >= upper.y || lower.z >= upper.z {
+        return error(at, "the box is empty");
+    }
+
+    Ok(Grid {
+        lower: lower,
+        upper: upper,
+        ..grid
+    })
+}
+
+// A medium, whose grid of densities, if it has one, is added to `grids`.
+fn medium(json: &Json, at: &str, base: &Path, grids: &mut Vec<Grid>) -> Result<Medium, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["absorption", "scattering", "g", "density"]));
+    medium_fields(obj, at, base, grids)
+}
+
+fn medium_fields(obj: &Object,
+                 at: &str,
+                 base: &Path,
+                 grids: &mut Vec<Grid>)
+                 -> Result<Medium, AppError> {
+    let g = try!(optional(obj, at, "g", 0.0, number));
+    if g <= -1.0 || g >= 1.0 {
+        return error(&join(at, "g"), "expected a number between -1 and 1");
+    }
+
+    let grid = try!(optional(obj, at, "density", None, |json, at| {
+        grid(json, at, base).map(|grid| {
+            grids.push(grid);
+            Some(grids.len() - 1)
+        })
+    }));
+
+    Ok(Medium {
+        absorption: try!(optional(obj, at, "absorption", Vector::zero(), shade)),
+        scattering: try!(optional(obj, at, "scattering", Vector::zero(), shade)),
+        g: g,
+        grid: grid,
+    })
+}
+
+// A medium outside every shape, filling the box from `lower` to `upper` if they are given.
+fn fog(json: &Json, at: &str, base: &Path, grids: &mut Vec<Grid>) -> Result<Fog, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["absorption", "scattering", "g", "density", "lower", "upper"]));
+    let medium = try!(medium_fields(obj, at, base, grids));
+    let bounds = if obj.contains_key("lower") || obj.contains_key("upper") {
+        let lower = try!(required(obj, at, "lower", vector));
+        let upper = try!(required(obj, at, "upper", vector));
+        if lower.x >= upper.x || lower.y >= upper.y || lower.z >= upper.z {
+            return error(at, "the box is empty");
+        }
+
+        Some((lower, upper))
+    } else {
+        None
+    };
+
+    Ok(Fog {
+        medium: medium,
+        bounds: bounds,
+    })
+}
+
+fn sphere(json: &Json,
+          at: &str,
+          base: &Path,
+          library: &mut Library,
+          grids: &mut Vec<Grid>)
+          -> Result<Shape, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj,
+                      at,
+                      &["name", "radius", "position", "emission", "color", "material", "textures",
+                        "medium"]));
+    let rad = try!(required(obj, at, "radius", number));
+    let p = try!(required(obj, at, "position", vector));
+    let (e, c, refl, textures) = try!(surface(obj, at, base, library));
+    let medium = try!(optional(obj, at, "medium", None, |json, at| {
+        medium(json, at, base, grids).map(Some)
+    }));
+    Ok(Shape::Sphere(Sphere {
+        textures: textures,
+        medium: medium,
+        ..Sphere::new(rad, p, e, c, refl)
+    }))
+}
+
+fn triangle(json: &Json, at: &str, base: &Path, library: &mut Library) -> Result<Shape, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj,
+                      at,
+                      &["name", "vertices", "emission", "color", "material", "textures"]));
+    let vertices = try!(required(obj, at, "vertices", |json, at| list(json, at, vector)));
+    if vertices.len() != 3 {
+        return error(&join(at, "vertices"), "expected three vertices");
+    }
+
+    let (e, c, refl, textures) = try!(surface(obj, at, base, library));
+    Ok(Shape::Triangle(Triangle {
+        textures: textures,
+        ..Triangle::new(vertices[0], vertices[1], vertices[2], e, c, refl)
+    }))
+}
+
+fn mesh(json: &Json,
+        at: &str,
+        base: &Path,
+        library: &mut Library,
+        grids: &mut Vec<Grid>)
+        -> Result<Shape, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj,
+                      at,
+                      &["name", "vertices", "normals", "uvs", "faces", "emission", "color",
+                        "material", "textures", "medium"]));
+    let vertices = try!(required(obj, at, "vertices", |json, at| list(json, at, vector)));
+    let normals = try!(optional(obj, at, "normals", Vec::new(), |json, at| list(json, at, vector)));
+    if !normals.is_empty() && normals.len() != vertices.len() {
+        return error(&join(at, "normals"), "expected one normal per vertex");
+    }
+
+    let uvs = try!(optional(obj, at, "uvs", Vec::new(), |json, at| list(json, at, uv)));
+    if !uvs.is_empty() && uvs.len() != vertices.len() {
+        return error(&join(at, "uvs"), "expected one texture coordinate per vertex");
+    }
+
+    let normals = normals.into_iter().map(|n| n.norm()).collect();
+    let faces = try!(required(obj, at, "faces", |json, at| list(json, at, face)));
+    for (i, &(i0, i1, i2)) in faces.iter().enumerate() {
+        if i0.max(i1).max(i2) >= vertices.len() {
+            return error(&format!("{}[{}]", join(at, "faces"), i),
+                         "vertex index out of range");
+        }
+    }
+
+    let (e, c, refl, textures) = try!(surface(obj, at, base, library));
+    let medium = try!(optional(obj, at, "medium", None, |json, at| {
+        medium(json, at, base, grids).map(Some)
+    }));
+    Ok(Shape::Mesh(Mesh {
+        textures: textures,
+        medium: medium,
+        ..Mesh::new(vertices, normals, uvs, faces, e, c, refl)
+    }))
+}
+
+// An OBJ file, scaled and then moved to `position`. Paths are relative to the scene file.
+fn model(json: &Json,
+         at: &str,
+         base: &Path,
+         library: &mut Library)
+         -> Result<Vec<Shape>, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["name", "path", "position", "scale"]));
+    let path = base.join(try!(required(obj, at, "path", string)));
+    let position = try!(optional(obj, at, "position", Vector::zero(), vector));
+    let scale = try!(optional(obj, at, "scale", 1.0, number));
+    let mut shapes = try!(obj::load(&path, library)
+                              .map_err(|err| AppError::new(format!("{}: {}", at, err))));
+    for shape in shapes.iter_mut() {
+        if let &mut Shape::Mesh(ref mut mesh) = shape {
+            place(mesh, position, scale);
+        }
+    }
+
+    Ok(shapes)
+}
+
+fn place(mesh: &mut Mesh, position: Vector, scale: f64) {
+    for v in mesh.vertices.iter_mut() {
+        *v = *v * scale + position;
+    }
+
+    // A negative scale turns the mesh inside out, so the faces wind the other way round and the
+    // normals point back outwards.
+    if scale < 0.0 {
+        for face in mesh.faces.iter_mut() {
+            *face = (face.0, face.2, face.1);
+        }
+
+        for n in mesh.normals.iter_mut() {
+            *n = *n * -1.0;
+        }
+    }
+}
+
+// An equirectangular image, relative to the scene file, that lights the scene from outside.
+fn environment(json: &Json, at: &str, base: &Path) -> Result<Environment, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["path", "rotation", "intensity"]));
+    let path = base.join(try!(required(obj, at, "path", string)));
+
+    // Light from outside is usually far brighter than 8-bit images can hold.
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("hdr") | Some("pfm") => {}
+        _ => return error(&join(at, "path"), "expected a .hdr or .pfm image"),
+    }
+
+    let image = try!(texture::load(&path, true)
+                         .map_err(|err| AppError::new(format!("{}: {}", at, err))));
+    Ok(Environment::Map(EnvironmentMap {
+        image: image,
+        rotation: try!(optional(obj, at, "rotation", 0.0, number)).to_radians(),
+        intensity: try!(optional(obj, at, "intensity", 1.0, number)),
+    }))
+}
+
+// Daylight, with the sun either in a given direction or where it would be at a place and time.
+fn sky(json: &Json, at: &str) -> Result<Environment, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj,
+                      at,
+                      &["sun", "latitude", "longitude", "timezone", "day", "time", "turbidity",
+                        "intensity", "sun_size"]));
+    let sun = if obj.contains_key("sun") {
+        try!(required(obj, at, "sun", vector)).norm()
+    } else {
+        let latitude = try!(required(obj, at, "latitude", number));
+        let longitude = try!(required(obj, at, "longitude", number));
+        let timezone = try!(optional(obj, at, "timezone", (longitude / 15.0).round(), number));
+        let day = try!(required(obj, at, "day", number));
+        let time = try!(required(obj, at, "time", number));
+        sky::sun_direction(latitude, longitude, timezone, day, time)
+    };
+
+    let sun_size = try!(optional(obj, at, "sun_size", sky::SUN_RADIUS.to_degrees() * 2.0, number));
+    Ok(Environment::Sky(Sky {
+        sun: sun,
+        turbidity: try!(optional(obj, at, "turbidity", 3.0, number)),
+        sun_radius: (sun_size / 2.0).to_radians(),
+        intensity: try!(optional(obj, at, "intensity", 0.02, number)),
+    }))
+}
+
+// A light with no area: a point, a spot, a directional light or a fixture with an IES profile.
+fn light(json: &Json, at: &str, base: &Path) -> Result<Light, AppError> {
+    let obj = try!(object(json, at));
+    let name = try!(required(obj, at, "type", string));
+    let down = Vector::new(0.0, -1.0, 0.0);
+    let (kind, intensity) = match name.as_str() {
+        "point" => {
+            try!(check_fields(obj, at, &["type", "position", "intensity"]));
+            (LightKind::Point, try!(required(obj, at, "intensity", vector)))
+        }
+        "spot" => {
+            try!(check_fields(obj,
+                              at,
+                              &["type", "position", "direction", "intensity", "angle", "falloff"]));
+            let angle = try!(optional(obj, at, "angle", 30.0, number));
+            let falloff = try!(optional(obj, at, "falloff", 5.0, number));
+            let cos_inner = (angle - falloff).max(0.0).to_radians().cos();
+            (LightKind::Spot(cos_inner, angle.to_radians().cos()),
+             try!(required(obj, at, "intensity", vector)))
+        }
+        "directional" => {
+            try!(check_fields(obj, at, &["type", "direction", "intensity"]));
+            (LightKind::Directional, try!(required(obj, at, "intensity", vector)))
+        }
+        "ies" => {
+            try!(check_fields(obj,
+                              at,
+                              &["type", "path", "position", "direction", "rotation", "intensity"]));
+            let path = base.join(try!(required(obj, at, "path", string)));
+            let profile = try!(ies::load(&path)
+                                   .map_err(|err| AppError::new(format!("{}: {}", at, err))));
+            let rotation = try!(optional(obj, at, "rotation", 0.0, number)).to_radians();
+            (LightKind::Ies(profile, rotation),
+             try!(optional(obj, at, "intensity", Vector::new(1.0, 1.0, 1.0), vector)))
+        }
+        _ => return error(at, &format!("unknown light \"{}\"", name)),
+    };
+
+    let position = match kind {
+        LightKind::Directional => Vector::zero(),
+        _ => try!(required(obj, at, "position", vector)),
+    };
+
+    Ok(Light {
+        kind: kind,
+        position: position,
+        direction: try!(optional(obj, at, "direction", down, vector)).norm(),
+        intensity: intensity,
+    })
+}
+
+fn camera(json: &Json, at: &str) -> Result<Ray, AppError> {
+    let obj = try!(object(json, at));
+    try!(check_fields(obj, at, &["origin", "direction"]));
+    let o = try!(required(obj, at, "origin", vector));
+    let d = try!(required(obj, at, "direction", vector));
+    Ok(Ray::new(o, d.norm()))
+}
+
+fn list<T, F>(json: &Json, at: &str, mut f: F) -> Result<Vec<T>, AppError>
+    where F: FnMut(&Json, &str) -> Result<T, AppError>
+{
+    let mut items = Vec::new();
+    for (i, json) in try!(array(json, at)).iter().enumerate() {
+        items.push(try!(f(json, &format!("{}[{}]", at, i))));
+    }
+
+    Ok(items)
+}
+
+pub fn parse(text: &str, base: &Path) -> Result<Session, AppError> {
+    let json = try!(Json::from_str(text).map_err(|err| match err {
+        ParserError::SyntaxError(code, line, col) => {
+            AppError::new(format!("line {}, column {}: {}", line, col, json::error_str(code)))
+        }
+        ParserError::IoError(err) => AppError::new(format!("{}", err)),
+    }));
+
+    let obj = try!(object(&json, "scene"));
+    try!(check_fields(obj,
+                      "",
+                      &["width", "height", "samples", "camera", "spheres", "triangles", "meshes",
+                        "models", "lights", "environment", "sky", "fog"]));
+    let width = try!(required(obj, "", "width", count));
+    let height = try!(required(obj, "", "height", count));
+    let samples = try!(optional(obj, "", "samples", 1, count));
+    let camera = try!(required(obj, "", "camera", camera));
+    let mut library = Library::new();
+    let mut grids = Vec::new();
+    let mut scene = Vec::new();
+    scene.extend(try!(optional(obj, "", "spheres", Vec::new(), |json, at| {
+        list(json, at, |json, at| sphere(json, at, base, &mut library, &mut grids))
+    })));
+    scene.extend(try!(optional(obj, "", "triangles", Vec::new(), |json, at| {
+        list(json, at, |json, at| triangle(json, at, base, &mut library))
+    })));
+    scene.extend(try!(optional(obj, "", "meshes", Vec::new(), |json, at| {
+        list(json, at, |json, at| mesh(json, at, base, &mut library, &mut grids))
+    })));
+    for shapes in try!(optional(obj, "", "models", Vec::new(), |json, at| {
+        list(json, at, |json, at| model(json, at, base, &mut library))
+    })) {
+        scene.extend(shapes);
+    }
+
+    let mut session = Session::new(width, height, samples, camera, scene, library.into_textures());
+    session.lights = try!(optional(obj, "", "lights", Vec::new(), |json, at| {
+        list(json, at, |json, at| light(json, at, base))
+    }));
+    session.fog = try!(optional(obj, "", "fog", None, |json, at| {
+        fog(json, at, base, &mut grids).map(Some)
+    }));
+    session.grids = grids;
+    session.environment = try!(optional(obj, "", "environment", None, |json, at| {
+        environment(json, at, base).map(Some)
+    }));
+
+    if obj.contains_key("sky") {
+        if session.environment.is_some() {
+            return error("sky", "a scene has either an environment or a sky, not both");
+        }
+
+        session.environment = Some(try!(required(obj, "", "sky", sky)));
+    }
+
+    // Fog that goes on for ever would swallow all the light from outside the scene.
+    let directional = session.lights.iter().any(|light| match light.kind {
+        LightKind::Directional => true,
+        _ => false,
+    });
+
+    if let Some(Fog { bounds: None, .. }) = session.fog {
+        if session.environment.is_some() || directional {
+            return error("fog",
+                         "fog in a scene lit from outside needs a box, from lower to upper");
+        }
+    }
+
+    Ok(session)
+}
+
+pub fn load(path: &Path) -> Result<Session, AppError> {
+    let mut text = String::new();
+    try!(File::open(path)
+             .and_then(|mut file| file.read_to_string(&mut text))
+             .map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+    parse(&text, path.parent().unwrap_or(Path::new("")))
+        .map_err(|err| AppError::new(format!("{}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{Mesh, Refl, Vector};
+    use std::path::Path;
+    use super::{parse, place};
+
+    // The message from parsing a scene with a camera and the given fields, or nothing if it parses.
+    fn error(fields: &str) -> String {
+        let text = format!(r#"{{"camera": {{"origin": [0, 0, 0], "direction": [0, 0, -1]}}, {}}}"#,
+                           fields);
+        parse(&text, Path::new("")).err().map(|err| err.to_string()).unwrap_or_default()
+    }
+
+    // The message for a scene of one sphere with the given fields.
+    fn sphere_error(fields: &str) -> String {
+        error(&format!(r#""width": 4, "height": 3, "spheres": [{{{}}}]"#, fields))
+    }
+
+    #[test]
+    fn sphere() {
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5]"#), "");
+    }
+
+    #[test]
+    fn missing_field() {
+        assert_eq!(error(r#""width": 4"#), "height: missing field");
+        assert_eq!(sphere_error(r#""position": [0, 0, -5]"#), "spheres[0].radius: missing field");
+    }
+
+    #[test]
+    fn wrong_type() {
+        assert_eq!(error(r#""width": 4, "height": 3.5"#),
+                   "height: expected a positive integer");
+        assert_eq!(sphere_error(r#""radius": "1", "position": [0, 0, -5]"#),
+                   "spheres[0].radius: expected a number");
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, "0", -5]"#),
+                   "spheres[0].position[1]: expected a number");
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5], "material": 3"#),
+                   "spheres[0].material: expected an object");
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5],
+                                 "material": {"type": "principled", "metallic": 1.5}"#),
+                   "spheres[0].material.metallic: expected a number from 0 to 1");
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5],
+                                 "material": {"type": "principled", "base_color": [0.5, 2, 0.5]}"#),
+                   "spheres[0].material.base_color: expected numbers from 0 to 1");
+    }
+
+    #[test]
+    fn unknown_material() {
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5], "material": "plastic""#),
+                   r#"spheres[0].material: unknown material "plastic""#);
+    }
+
+    #[test]
+    fn null_emission() {
+        assert_eq!(sphere_error(r#""radius": 1, "position": [0, 0, -5], "material": "null",
+                                 "emission": [1, 1, 1]"#),
+                   "spheres[0].emission: a shape with no surface cannot give off light");
+    }
+
+    #[test]
+    fn negative_scale() {
+        // The faces of a mirrored mesh still wind anticlockwise seen from where its normals point.
+        let mut mesh = Mesh::new(vec![Vector::zero(), Vector::new(1.0, 0.0, 0.0),
+                                      Vector::new(0.0, 1.0, 0.0)],
+                                 vec![Vector::new(0.0, 0.0, 1.0); 3],
+                                 Vec::new(),
+                                 vec![(0, 1, 2)],
+                                 Vector::zero(),
+                                 Vector::new(1.0, 1.0, 1.0),
+                                 Refl::Diff);
+        place(&mut mesh, Vector::new(5.0, 0.0, 0.0), -2.0);
+        let (i0, i1, i2) = mesh.faces[0];
+        let v = &mesh.vertices;
+        let winding = (v[i1] - v[i0]).cross(v[i2] - v[i0]);
+        assert_eq!(v[1].x, 3.0);
+        assert!(winding.dot(mesh.normals[0]) > 0.0);
+        assert_eq!(mesh.normals[0].z, -1.0);
+    }
+
+    #[test]
+    fn environment_format() {
+        assert_eq!(error(r#""width": 4, "height": 3, "environment": {"path": "sky.png"}"#),
+                   "environment.path: expected a .hdr or .pfm image");
+    }
+}
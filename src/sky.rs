@@ -0,0 +1,155 @@
+use api::{Sky, Vector};
+use std::f64;
+
+// Luminance of the sun's disc outside the atmosphere, in kcd/m², for a disc of SUN_RADIUS.
+const SUN_LUMINANCE: f64 = 2.0e6;
+
+// Angular radius of the sun as seen from the earth.
+pub const SUN_RADIUS: f64 = 0.00465;
+
+// Direction towards the sun, with y up, x east and z south, at a latitude and longitude in degrees
+// (north and east positive), on a day of the year and at a time in hours in a time zone given in
+// hours from UTC. Follows the appendix to Preetham, Shirley and Smits 1999.
+pub fn sun_direction(latitude: f64, longitude: f64, timezone: f64, day: f64, time: f64) -> Vector {
+    let latitude = latitude.to_radians();
+    let meridian = (timezone * 15.0).to_radians();
+    let solar_time = time + 0.170 * (4.0 * f64::consts::PI * (day - 80.0) / 373.0).sin() -
+                     0.129 * (2.0 * f64::consts::PI * (day - 8.0) / 355.0).sin() +
+                     12.0 * (longitude.to_radians() - meridian) / f64::consts::PI;
+    let declination = 0.4093 * (2.0 * f64::consts::PI * (day - 81.0) / 368.0).sin();
+    let hour_angle = f64::consts::PI * (solar_time - 12.0) / 12.0;
+    let east = -declination.cos() * hour_angle.sin();
+    let north = latitude.cos() * declination.sin() -
+                latitude.sin() * declination.cos() * hour_angle.cos();
+    let up = latitude.sin() * declination.sin() +
+             latitude.cos() * declination.cos() * hour_angle.cos();
+    Vector::new(east, up, -north).norm()
+}
+
+// Perez's formula for the relative distribution of light over the sky, for a direction at angle
+// `theta` from the zenith and `gamma` from the sun.
+fn perez(c: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    (1.0 + c[0] * (c[1] / theta.cos()).exp()) *
+    (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Vector {
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    Vector::new((3.2406 * cx - 1.5372 * luminance - 0.4986 * cz).max(0.0),
+                (-0.9689 * cx + 1.8758 * luminance + 0.0415 * cz).max(0.0),
+                (0.0557 * cx - 0.2040 * luminance + 1.0570 * cz).max(0.0))
+}
+
+// The sun as a light: a disc around `dir` of constant radiance, within which the cosine of the
+// angle from `dir` is above `cos_max`.
+pub struct Sun {
+    pub dir: Vector,
+    pub cos_max: f64,
+    pub radiance: Vector,
+}
+
+impl Sun {
+    pub fn radiance(&self, dir: Vector) -> Vector {
+        if dir.dot(self.dir) >= self.cos_max {
+            self.radiance
+        } else {
+            Vector::zero()
+        }
+    }
+}
+
+// Preetham's model with its coefficients worked out for one turbidity and sun position.
+pub struct Model {
+    sun: Vector,
+    theta_sun: f64,
+    coefficients: [[f64; 5]; 3],
+    zenith: [f64; 3],
+    // Perez's distribution at the zenith, which the zenith values are given for.
+    at_zenith: [f64; 3],
+    intensity: f64,
+}
+
+impl Model {
+    pub fn new(sky: &Sky) -> Self {
+        let t = sky.turbidity;
+        let sun = sky.sun.norm();
+
+        // The model only holds for a sun above the horizon, so a lower sun gives the sky at sunset.
+        let theta = sun.y.max(0.0).min(1.0).acos().min(f64::consts::FRAC_PI_2 - 0.01);
+        let (t2, theta2, theta3) = (t * t, theta * theta, theta * theta * theta);
+        let chi = (4.0 / 9.0 - t / 120.0) * (f64::consts::PI - 2.0 * theta);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let x = t2 * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta) +
+                t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394) +
+                (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let y = t2 * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta) +
+                t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516) +
+                (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+        let coefficients = [[0.1787 * t - 1.4630,
+                             -0.3554 * t + 0.4275,
+                             -0.0227 * t + 5.3251,
+                             0.1206 * t - 2.5771,
+                             -0.0670 * t + 0.3703],
+                            [-0.0193 * t - 0.2592,
+                             -0.0665 * t + 0.0008,
+                             -0.0004 * t + 0.2125,
+                             -0.0641 * t - 0.8989,
+                             -0.0033 * t + 0.0452],
+                            [-0.0167 * t - 0.2608,
+                             -0.0950 * t + 0.0092,
+                             -0.0079 * t + 0.2102,
+                             -0.0441 * t - 1.6537,
+                             -0.0109 * t + 0.0529]];
+        let perez_zenith = |i: usize| perez(&coefficients[i], 0.0, theta);
+        Model {
+            sun: sun,
+            theta_sun: theta,
+            coefficients: coefficients,
+            zenith: [luminance, x, y],
+            at_zenith: [perez_zenith(0), perez_zenith(1), perez_zenith(2)],
+            intensity: sky.intensity,
+        }
+    }
+
+    // Light from the sky in direction `dir`, leaving out the sun's disc. Below the horizon the sky
+    // keeps its colour at the horizon.
+    pub fn radiance(&self, dir: Vector) -> Vector {
+        let theta = dir.y.max(0.001).min(1.0).acos();
+        let gamma = dir.dot(self.sun).max(-1.0).min(1.0).acos();
+        let mut value = [0.0; 3];
+        for i in 0..3 {
+            let c = &self.coefficients[i];
+            value[i] = self.zenith[i] * perez(c, theta, gamma) / self.at_zenith[i];
+        }
+
+        xyy_to_rgb(value[1], value[2], value[0]) * self.intensity
+    }
+
+    // The sun, dimmed and reddened by Rayleigh and aerosol scattering along its path through the
+    // atmosphere, or None once it has set. A larger disc is made dimmer so that it gives the same
+    // light.
+    pub fn sun(&self, sky: &Sky) -> Option<Sun> {
+        if self.sun.y <= 0.0 {
+            return None;
+        }
+
+        let theta_degrees = self.theta_sun.to_degrees();
+        let mass = 1.0 / (self.theta_sun.cos() + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+        let beta = 0.04608 * sky.turbidity - 0.04586;
+        let transmittance = |wavelength: f64| {
+            let rayleigh = (-0.008735 * wavelength.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * wavelength.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        };
+
+        let cos_max = sky.sun_radius.cos();
+        let scale = (1.0 - SUN_RADIUS.cos()) / (1.0 - cos_max);
+        Some(Sun {
+            dir: self.sun,
+            cos_max: cos_max,
+            radiance: Vector::new(transmittance(0.65), transmittance(0.55), transmittance(0.45)) *
+                      (SUN_LUMINANCE * scale * self.intensity),
+        })
+    }
+}
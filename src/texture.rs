@@ -0,0 +1,409 @@
+use api::{Image, Pattern, Procedural, Texture, Vector};
+use image::{self, hdr};
+use std::collections::HashMap;
+use std::f64;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use super::AppError;
+
+fn error(path: &Path, err: image::ImageError) -> AppError {
+    AppError::new(format!("{}: {}", path.display(), err))
+}
+
+// Portable float map: a text header giving the channels, size and byte order, then rows of 32-bit
+// floats from the bottom of the image to the top.
+fn parse_pfm(data: &[u8]) -> Result<Image, String> {
+    let invalid = || "invalid PFM file".to_string();
+
+    // The header is three lines, or at least three whitespace-separated fields, then a single
+    // whitespace character.
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while fields.len() < 4 && i < data.len() {
+        if (data[i] as char).is_whitespace() {
+            if i > start {
+                fields.push(String::from_utf8_lossy(&data[start..i]).into_owned());
+            }
+
+            start = i + 1;
+        }
+
+        i += 1;
+    }
+
+    if fields.len() < 4 {
+        return Err(invalid());
+    }
+
+    let channels = match fields[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid()),
+    };
+
+    let width: usize = try!(fields[1].parse().map_err(|_| invalid()));
+    let height: usize = try!(fields[2].parse().map_err(|_| invalid()));
+    let scale: f32 = try!(fields[3].parse().map_err(|_| invalid()));
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+
+    let body = &data[start..];
+    let size = try!(width.checked_mul(height)
+                         .and_then(|n| n.checked_mul(channels * 4))
+                         .ok_or_else(invalid));
+    if body.len() < size {
+        return Err(invalid());
+    }
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for c in 0..3 {
+                let i = ((y * width + x) * channels + c % channels) * 4;
+                let bytes = [body[i], body[i + 1], body[i + 2], body[i + 3]];
+                let bits = if scale < 0.0 {
+                    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 |
+                    (bytes[3] as u32) << 24
+                } else {
+                    (bytes[3] as u32) | (bytes[2] as u32) << 8 | (bytes[1] as u32) << 16 |
+                    (bytes[0] as u32) << 24
+                };
+
+                pixels.push(unsafe { mem::transmute::<u32, f32>(bits) });
+            }
+        }
+    }
+
+    Ok(Image {
+        width: width,
+        height: height,
+        pixels: pixels,
+    })
+}
+
+// Loads an image as linear RGB. Radiance .hdr and .pfm files are linear already; 8-bit images are
+// decoded from sRGB when `srgb` is set, as for colours, and taken as they are otherwise, as for
+// roughness.
+pub fn load(path: &Path, srgb: bool) -> Result<Image, AppError> {
+    let image = try!(decode(path, srgb));
+    if image.width == 0 || image.height == 0 {
+        return Err(AppError::new(format!("{}: empty image", path.display())));
+    }
+
+    Ok(image)
+}
+
+fn decode(path: &Path, srgb: bool) -> Result<Image, AppError> {
+    if path.extension().and_then(|ext| ext.to_str()) == Some("pfm") {
+        let mut data = Vec::new();
+        try!(File::open(path)
+                 .and_then(|mut file| file.read_to_end(&mut data))
+                 .map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+        return parse_pfm(&data)
+                   .map_err(|message| AppError::new(format!("{}: {}", path.display(), message)));
+    }
+
+    if path.extension().and_then(|ext| ext.to_str()) == Some("hdr") {
+        let file = try!(File::open(path)
+                            .map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+        let decoder = try!(hdr::HDRDecoder::new(BufReader::new(file))
+                               .map_err(|err| error(path, err)));
+        let metadata = decoder.metadata();
+        let pixels = try!(decoder.read_image_hdr().map_err(|err| error(path, err)));
+        return Ok(Image {
+            width: metadata.width as usize,
+            height: metadata.height as usize,
+            pixels: pixels.iter().flat_map(|p| p.data.to_vec()).collect(),
+        });
+    }
+
+    let image = try!(image::open(path).map_err(|err| error(path, err))).to_rgb();
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    let pixels = image.into_raw()
+                      .into_iter()
+                      .map(|b| {
+                          let x = b as f32 / 255.0;
+                          if srgb {
+                              x.powf(2.2)
+                          } else {
+                              x
+                          }
+                      })
+                      .collect();
+
+    Ok(Image {
+        width: width,
+        height: height,
+        pixels: pixels,
+    })
+}
+
+impl Image {
+    // The pixel at column `x` and row `y`. Columns wrap around, and so do rows unless `wrap_y` is
+    // false, when they stop at the top and bottom of the image.
+    fn texel(&self, x: isize, y: isize, wrap_y: bool) -> Vector {
+        let x = x.wrapping_rem(self.width as isize);
+        let y = if wrap_y {
+            y.wrapping_rem(self.height as isize)
+        } else {
+            y.max(0).min(self.height as isize - 1)
+        };
+
+        let x = if x < 0 { x + self.width as isize } else { x } as usize;
+        let y = if y < 0 { y + self.height as isize } else { y } as usize;
+        let i = (y * self.width + x) * 3;
+        Vector::new(self.pixels[i] as f64,
+                    self.pixels[i + 1] as f64,
+                    self.pixels[i + 2] as f64)
+    }
+
+    // Bilinear lookup. Coordinates wrap around, and v runs from the bottom of the image to the top.
+    pub fn lookup(&self, uv: (f64, f64)) -> Vector {
+        self.bilinear(uv, true)
+    }
+
+    // Bilinear lookup in a latitude-longitude image, where u wraps around but v stops at the poles
+    // rather than blending one into the other.
+    pub fn lookup_equirect(&self, uv: (f64, f64)) -> Vector {
+        self.bilinear(uv, false)
+    }
+
+    fn bilinear(&self, uv: (f64, f64), wrap_v: bool) -> Vector {
+        let x = uv.0 * self.width as f64 - 0.5;
+        let y = (1.0 - uv.1) * self.height as f64 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let texel = |x, y| self.texel(x, y, wrap_v);
+        (texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx) * (1.0 - fy) +
+        (texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx) * fy
+    }
+}
+
+// Hashes a lattice point, standing in for the permutation table of Perlin's noise so that
+// procedural textures need no state.
+fn hash(x: i64, y: i64, z: i64) -> u32 {
+    let mut h = (x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663) ^
+                 z.wrapping_mul(83492791)) as u32;
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^ (h >> 15)
+}
+
+// Dot product of the offset from a lattice point with one of the twelve edge directions of a cube.
+fn grad(h: u32, x: f64, y: f64, z: f64) -> f64 {
+    let h = h & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+// Perlin's improved noise, from -1 to 1.
+fn noise(p: Vector) -> f64 {
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (x, y, z) = (p.x - x0, p.y - y0, p.z - z0);
+    let (xi, yi, zi) = (x0 as i64, y0 as i64, z0 as i64);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        grad(hash(xi + dx, yi + dy, zi + dz),
+             x - dx as f64,
+             y - dy as f64,
+             z - dz as f64)
+    };
+
+    lerp(w,
+         lerp(v,
+              lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+              lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+         lerp(v,
+              lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+              lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+}
+
+// Fractional Brownian motion: octaves of noise, each at twice the frequency and half the
+// amplitude of the last, normalised to run from -1 to 1.
+fn fbm(p: Vector, octaves: usize) -> f64 {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut p = p;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(p);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+
+    sum / total
+}
+
+// Like fbm, but summing the magnitude of each octave, which gives the creases of marble veins.
+fn turbulence(p: Vector, octaves: usize) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut p = p;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(p).abs();
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+
+    sum
+}
+
+impl Procedural {
+    fn value(&self, p: Vector) -> f64 {
+        let t = match self.pattern {
+            Pattern::Checker => {
+                let cells = p.x.floor() + p.y.floor() + p.z.floor();
+                if cells % 2.0 == 0.0 { 0.0 } else { 1.0 }
+            }
+            Pattern::Gradient => p.x,
+            Pattern::Noise(octaves) => 0.5 + 0.5 * fbm(p, octaves),
+            Pattern::Marble(octaves) => {
+                0.5 - 0.5 * ((p.x + 4.0 * turbulence(p, octaves)) * f64::consts::PI).cos()
+            }
+            // Rings around the z axis, warped by noise.
+            Pattern::Wood(octaves) => {
+                let r = (p.x * p.x + p.y * p.y).sqrt() + 0.25 * fbm(p, octaves);
+                r - r.floor()
+            }
+        };
+
+        t.max(0.0).min(1.0)
+    }
+
+    pub fn lookup(&self, uv: (f64, f64), pos: Vector) -> Vector {
+        let p = if self.uv {
+            Vector::new(uv.0, uv.1, 0.0)
+        } else {
+            pos
+        };
+
+        let t = self.value(p * self.scale);
+        self.a * (1.0 - t) + self.b * t
+    }
+}
+
+impl Texture {
+    pub fn lookup(&self, uv: (f64, f64), pos: Vector) -> Vector {
+        match self {
+            &Texture::Image(ref image) => image.lookup(uv),
+            &Texture::Procedural(ref procedural) => procedural.lookup(uv, pos),
+        }
+    }
+}
+
+// Textures used by a scene, with each image loaded once however many shapes refer to it. Shapes
+// refer to them by index into Session::textures.
+pub struct Library {
+    textures: Vec<Texture>,
+    indices: HashMap<(PathBuf, bool), usize>,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Library {
+            textures: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    pub fn load(&mut self, path: &Path, srgb: bool) -> Result<usize, AppError> {
+        let key = (path.to_path_buf(), srgb);
+        if let Some(&index) = self.indices.get(&key) {
+            return Ok(index);
+        }
+
+        let index = self.textures.len();
+        self.textures.push(Texture::Image(try!(load(path, srgb))));
+        self.indices.insert(key, index);
+        Ok(index)
+    }
+
+    pub fn add(&mut self, procedural: Procedural) -> usize {
+        self.textures.push(Texture::Procedural(procedural));
+        self.textures.len() - 1
+    }
+
+    pub fn into_textures(self) -> Vec<Texture> {
+        self.textures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::Image;
+    use std::mem;
+    use super::parse_pfm;
+
+    // A little-endian PFM file.
+    fn pfm(header: &str, floats: &[f32]) -> Vec<u8> {
+        let mut data = header.as_bytes().to_vec();
+        for f in floats {
+            let bits = unsafe { mem::transmute::<f32, u32>(*f) };
+            data.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8,
+                                     (bits >> 24) as u8]);
+        }
+
+        data
+    }
+
+    #[test]
+    fn pfm_rows() {
+        let image = parse_pfm(&pfm("PF\n1 2\n-1.0\n", &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0])).unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert_eq!(image.pixels, vec![4.0, 5.0, 6.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn pfm_truncated() {
+        assert!(parse_pfm(&pfm("PF\n2 2\n-1.0\n", &[1.0; 11])).is_err());
+        assert!(parse_pfm(&pfm("PF\n2 2\n", &[])).is_err());
+    }
+
+    #[test]
+    fn pfm_empty() {
+        assert!(parse_pfm(&pfm("PF\n0 2\n-1.0\n", &[])).is_err());
+    }
+
+    #[test]
+    fn pfm_too_large() {
+        assert!(parse_pfm(&pfm("PF\n18446744073709551615 2\n-1.0\n", &[1.0; 6])).is_err());
+    }
+
+    #[test]
+    fn equirect_poles() {
+        let image = Image {
+            width: 1,
+            height: 2,
+            pixels: vec![1.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+        };
+
+        assert_eq!(image.lookup_equirect((0.5, 1.0)).x, 1.0);
+        assert_eq!(image.lookup_equirect((0.5, 0.0)).x, 0.0);
+        assert_eq!(image.lookup((0.5, 1.0)).x, 0.5);
+    }
+}
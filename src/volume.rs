@@ -0,0 +1,163 @@
+use api::{Grid, Ray, Vector};
+use medium;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use super::AppError;
+
+fn int(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn float(bytes: &[u8]) -> f32 {
+    unsafe { mem::transmute::<u32, f32>(int(bytes)) }
+}
+
+// Mitsuba's grid volume format: "VOL" and version 3, then little-endian 32-bit integers for the
+// encoding (1 for floats, 3 for bytes), the resolution in x, y and z and the number of channels,
+// six floats for the bounding box, and the voxels with x varying fastest. Several channels are
+// averaged into one density.
+pub fn load(path: &Path) -> Result<Grid, AppError> {
+    let mut data = Vec::new();
+    try!(File::open(path)
+             .and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|err| AppError::new(format!("{}: {}", path.display(), err))));
+    parse(&data).map_err(|message| AppError::new(format!("{}: {}", path.display(), message)))
+}
+
+// The grid in the bytes of a VOL file. Error messages leave out the path, which `load` adds.
+pub fn parse(data: &[u8]) -> Result<Grid, String> {
+    let invalid = |message: &str| message.to_string();
+    if data.len() < 48 || &data[0..3] != b"VOL" || data[3] != 3 {
+        return Err(invalid("not a version 3 VOL file"));
+    }
+
+    let encoding = int(&data[4..8]);
+    let resolution = (int(&data[8..12]) as usize,
+                      int(&data[12..16]) as usize,
+                      int(&data[16..20]) as usize);
+    let channels = int(&data[20..24]) as usize;
+    let bounds: Vec<f64> = (0..6).map(|i| float(&data[24 + i * 4..28 + i * 4]) as f64).collect();
+    let size = match encoding {
+        1 => 4,
+        3 => 1,
+        _ => return Err(invalid("only float and byte encodings are supported")),
+    };
+
+    let body = &data[48..];
+    let voxels = resolution.0.checked_mul(resolution.1).and_then(|n| n.checked_mul(resolution.2));
+    let length = voxels.and_then(|n| n.checked_mul(channels)).and_then(|n| n.checked_mul(size));
+    let voxels = match (voxels, length) {
+        (Some(voxels), Some(length)) if voxels > 0 && channels > 0 && body.len() >= length => {
+            voxels
+        }
+        _ => return Err(invalid("truncated voxel data")),
+    };
+
+    let value = |i: usize| {
+        if size == 4 {
+            float(&body[i * 4..i * 4 + 4])
+        } else {
+            body[i] as f32 / 255.0
+        }
+    };
+
+    let mut density = Vec::with_capacity(voxels);
+    for v in 0..voxels {
+        let sum = (0..channels).fold(0.0, |sum, c| sum + value(v * channels + c));
+        density.push((sum / channels as f32).max(0.0));
+    }
+
+    let max_density = density.iter().fold(0.0f32, |max, &d| max.max(d)) as f64;
+
+    Ok(Grid {
+        lower: Vector::new(bounds[0], bounds[1], bounds[2]),
+        upper: Vector::new(bounds[3], bounds[4], bounds[5]),
+        resolution: resolution,
+        density: density,
+        max_density: max_density,
+    })
+}
+
+impl Grid {
+    fn voxel(&self, x: isize, y: isize, z: isize) -> f64 {
+        let (nx, ny, nz) = self.resolution;
+        let x = x.max(0).min(nx as isize - 1) as usize;
+        let y = y.max(0).min(ny as isize - 1) as usize;
+        let z = z.max(0).min(nz as isize - 1) as usize;
+        self.density[(z * ny + y) * nx + x] as f64
+    }
+
+    // Density at `pos`, interpolated between voxel centres and zero outside the box.
+    pub fn density(&self, pos: Vector) -> f64 {
+        let extent = self.upper - self.lower;
+        let p = pos - self.lower;
+        let (u, v, w) = (p.x / extent.x, p.y / extent.y, p.z / extent.z);
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 || w < 0.0 || w > 1.0 {
+            return 0.0;
+        }
+
+        let x = u * self.resolution.0 as f64 - 0.5;
+        let y = v * self.resolution.1 as f64 - 0.5;
+        let z = w * self.resolution.2 as f64 - 0.5;
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as isize, y0 as isize, z0 as isize);
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let plane = |z: isize| {
+            lerp(lerp(self.voxel(x0, y0, z), self.voxel(x0 + 1, y0, z), fx),
+                 lerp(self.voxel(x0, y0 + 1, z), self.voxel(x0 + 1, y0 + 1, z), fx),
+                 fy)
+        };
+
+        lerp(plane(z0), plane(z0 + 1), fz)
+    }
+
+    // The part of `ray` up to distance `max` that lies in the box, if any.
+    pub fn clip(&self, ray: Ray, max: f64) -> Option<(f64, f64)> {
+        medium::clip(self.lower, self.upper, ray, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use super::parse;
+
+    // A single-channel float grid filling the unit cube.
+    fn vol(resolution: (u32, u32, u32), values: &[f32]) -> Vec<u8> {
+        let mut words = vec![1, resolution.0, resolution.1, resolution.2, 1];
+        for &x in [0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0].iter().chain(values) {
+            words.push(unsafe { mem::transmute::<f32, u32>(x) });
+        }
+
+        let mut data = b"VOL\x03".to_vec();
+        for w in words {
+            data.extend_from_slice(&[w as u8, (w >> 8) as u8, (w >> 16) as u8, (w >> 24) as u8]);
+        }
+
+        data
+    }
+
+    #[test]
+    fn grid() {
+        let grid = parse(&vol((2, 1, 1), &[0.5, 2.0])).unwrap();
+        assert_eq!(grid.resolution, (2, 1, 1));
+        assert_eq!(grid.density, vec![0.5, 2.0]);
+        assert_eq!(grid.max_density, 2.0);
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(parse(&vol((2, 2, 1), &[1.0; 3])).is_err());
+        assert!(parse(&vol((1, 1, 1), &[])[..40]).is_err());
+        assert!(parse(&vol((0, 2, 1), &[])).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        let data = vol((u32::max_value(), u32::max_value(), 16), &[1.0]);
+        assert!(parse(&data).is_err());
+    }
+}